@@ -0,0 +1,253 @@
+use clap::Parser;
+use q2_proto::demo::DemoReader;
+use q2_proto::demo_edit::FRAME_TIME;
+use q2_proto::error::Q2ProtoError;
+use q2_proto::mvd::{MvdEvent, MvdReader, MVD_MAGIC};
+use q2_proto::obituary::{parse_obituary, Kill};
+use q2_proto::objects::{
+    player_name, player_names, MuzzleFlash, PrintLevel, CS_MODELS, CS_NAME, MAX_STATS,
+    STAT_PICKUP_STRING,
+};
+use q2_proto::text::decode;
+use q2_proto::ClientEvent;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::process;
+
+#[derive(Parser)]
+#[command(author, version, about = "q2-analyze: match stats from quake 2 demos")]
+struct Args {
+    /// the .dm2 or .mvd2 to read
+    demo: String,
+
+    /// indent the json
+    #[arg(short, long)]
+    pretty: bool,
+}
+
+#[derive(Serialize)]
+struct Report {
+    map: String,
+    map_name: String,
+    // seconds
+    duration: f64,
+    players: Vec<PlayerReport>,
+}
+
+#[derive(Serialize, Default)]
+struct PlayerReport {
+    name: String,
+    frags: i32,
+    deaths: u32,
+    // deaths nobody else gets the credit for: the world, or their own doing.
+    suicides: u32,
+    // shots, going by muzzle flashes.
+    weapons: BTreeMap<String, u32>,
+    // only there for .mvd2. a .dm2 has nobody's stats but the recorder's. a low count: the
+    // same item picked up again while its name is still showing (3 seconds) counts once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pickups: Option<BTreeMap<String, u32>>,
+}
+
+#[derive(Default)]
+struct Analyzer {
+    configstrings: HashMap<u16, Vec<u8>>,
+    // the first level in the demo is the one that counts.
+    map: Option<String>,
+    map_name: Option<String>,
+    frames: u32,
+    players: BTreeMap<String, PlayerReport>,
+    // whether we get to see everyone's stats, and so their pickups.
+    all_stats: bool,
+    // the pickup each slot was last shown, so each one counts once.
+    last_pickup: HashMap<u8, i16>,
+}
+
+impl Analyzer {
+    fn configstring(&mut self, index: u16, value: Vec<u8>) {
+        let text = decode(&value);
+        if index == CS_NAME && self.map_name.is_none() {
+            self.map_name = Some(text);
+        } else if index == CS_MODELS + 1 && self.map.is_none() {
+            // the world model, maps/<map>.bsp
+            let map = text.trim_start_matches("maps/").trim_end_matches(".bsp");
+            self.map = Some(map.to_string());
+        }
+
+        self.configstrings.insert(index, value);
+    }
+
+    fn player_name(&self, slot: u8) -> Option<String> {
+        player_name(&self.configstrings, slot as u16)
+    }
+
+    fn player(&mut self, name: &str) -> &mut PlayerReport {
+        let all_stats = self.all_stats;
+        self.players
+            .entry(name.to_string())
+            .or_insert_with(|| PlayerReport {
+                name: name.to_string(),
+                pickups: all_stats.then(BTreeMap::new),
+                ..Default::default()
+            })
+    }
+
+    fn print(&mut self, level: PrintLevel, text: &[u8]) {
+        // obituaries are all medium.
+        if level != PrintLevel::MEDIUM {
+            return;
+        }
+
+        match parse_obituary(text, &player_names(&self.configstrings)) {
+            Some(Kill {
+                victim,
+                killer: Some(killer),
+                ..
+            }) => {
+                self.player(&killer).frags += 1;
+                self.player(&victim).deaths += 1;
+            }
+            Some(Kill { victim, .. }) => {
+                let victim = self.player(&victim);
+                victim.frags -= 1;
+                victim.deaths += 1;
+                victim.suicides += 1;
+            }
+            None => {}
+        }
+    }
+
+    fn muzzle_flash(&mut self, flash: &MuzzleFlash) {
+        if let (Some(weapon), Some(name)) = (
+            flash.weapon_name(),
+            flash.player().and_then(|slot| self.player_name(slot)),
+        ) {
+            *self
+                .player(&name)
+                .weapons
+                .entry(weapon.to_string())
+                .or_default() += 1;
+        }
+    }
+
+    // the pickup string stays up for a few seconds, so a pickup is when it changes. grabbing
+    // the same thing again before it goes away only pushes the timeout back, and nothing
+    // in the player state changes, so those we miss.
+    fn stats(&mut self, slot: u8, stats: &[Option<i16>; MAX_STATS]) {
+        let pickup = stats[STAT_PICKUP_STRING].unwrap_or(0);
+        if self.last_pickup.insert(slot, pickup) == Some(pickup) || pickup <= 0 {
+            return;
+        }
+
+        let item = self
+            .configstrings
+            .get(&(pickup as u16))
+            .map(|item| decode(item));
+        if let (Some(item), Some(name)) = (item, self.player_name(slot)) {
+            if let Some(pickups) = &mut self.player(&name).pickups {
+                *pickups.entry(item).or_default() += 1;
+            }
+        }
+    }
+
+    fn report(mut self) -> Report {
+        // everyone who was there, even if they did nothing.
+        for slot in 0..=255 {
+            if let Some(name) = self.player_name(slot) {
+                self.player(&name);
+            }
+        }
+
+        let mut players: Vec<PlayerReport> = self.players.into_values().collect();
+        players.sort_by(|a, b| b.frags.cmp(&a.frags).then_with(|| a.name.cmp(&b.name)));
+
+        Report {
+            map: self.map.unwrap_or_default(),
+            map_name: self.map_name.unwrap_or_default(),
+            duration: (FRAME_TIME * self.frames).as_secs_f64(),
+            players,
+        }
+    }
+}
+
+// only whoever recorded it has their player state in the demo, so there are no pickups
+// to count.
+fn analyze_dm2(path: &str, analyzer: &mut Analyzer) -> Result<(), Q2ProtoError> {
+    for evt in DemoReader::open(path)? {
+        match evt? {
+            ClientEvent::ConfigString(index, value) => analyzer.configstring(index, value),
+            ClientEvent::Print(level, text) => analyzer.print(level, &text),
+            ClientEvent::MuzzleFlash(flash) => analyzer.muzzle_flash(&flash),
+            ClientEvent::Frame(_) => analyzer.frames += 1,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn analyze_mvd(path: &str, analyzer: &mut Analyzer) -> Result<(), Q2ProtoError> {
+    for evt in MvdReader::open(path)? {
+        match evt? {
+            MvdEvent::ConfigString(index, value) => analyzer.configstring(index, value),
+            MvdEvent::Print(level, text) => analyzer.print(level, &text),
+            MvdEvent::Multicast { events, .. } => {
+                for evt in events {
+                    match evt {
+                        ClientEvent::MuzzleFlash(flash) => analyzer.muzzle_flash(&flash),
+                        ClientEvent::Print(level, text) => analyzer.print(level, &text),
+                        _ => {}
+                    }
+                }
+            }
+            // everyone's player state is in there.
+            MvdEvent::Frame(frame) => {
+                analyzer.frames += 1;
+                for player in &frame.players {
+                    analyzer.stats(player.number, &player.state.stats);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn analyze(path: &str) -> Result<Report, Q2ProtoError> {
+    let mut magic = [0u8; 4];
+    let is_mvd = File::open(path)?.read_exact(&mut magic).is_ok() && &magic == MVD_MAGIC;
+
+    let mut analyzer = Analyzer {
+        all_stats: is_mvd,
+        ..Default::default()
+    };
+    if is_mvd {
+        analyze_mvd(path, &mut analyzer)?;
+    } else {
+        analyze_dm2(path, &mut analyzer)?;
+    }
+
+    Ok(analyzer.report())
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let report = match analyze(&args.demo) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("couldn't read {}: {}", args.demo, e);
+            process::exit(1);
+        }
+    };
+
+    let json = if args.pretty {
+        serde_json::to_string_pretty(&report)
+    } else {
+        serde_json::to_string(&report)
+    };
+    println!("{}", json.expect("report didn't serialize"));
+}
@@ -0,0 +1,219 @@
+use clap::{Parser, Subcommand};
+use q2_proto::demo::{DemoReader, DemoWriter};
+use q2_proto::demo_edit::{
+    cut_demo, extract_pov, filter_demo, merge_demos, CutRange, DemoPosition,
+};
+use q2_proto::error::Q2ProtoError;
+use q2_proto::mvd::MvdReader;
+use q2_proto::{ClientEvent, ClientEventKind};
+use std::process;
+use std::time::Duration;
+
+// a demo can't play without these.
+const REQUIRED: [ClientEventKind; 4] = [
+    ClientEventKind::ServerData,
+    ClientEventKind::ConfigString,
+    ClientEventKind::DeltaEntity,
+    ClientEventKind::Frame,
+];
+
+#[derive(Parser)]
+#[command(
+    author,
+    version,
+    about = "q2-demo: cut, filter and merge quake 2 demos"
+)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// keep part of a demo
+    Cut {
+        input: String,
+        output: String,
+
+        /// start this many seconds in
+        #[arg(long, conflicts_with = "start_frame")]
+        start: Option<f64>,
+
+        /// stop this many seconds in
+        #[arg(long, conflicts_with = "end_frame")]
+        end: Option<f64>,
+
+        /// start at this frame
+        #[arg(long)]
+        start_frame: Option<u32>,
+
+        /// stop after this frame
+        #[arg(long)]
+        end_frame: Option<u32>,
+    },
+
+    /// drop or keep kinds of messages (print, stufftext, centerprint, sound, tempentity,
+    /// muzzleflash, muzzleflash2, layout, inventory...). the level and the precache that
+    /// loads it are always kept.
+    Filter {
+        input: String,
+        output: String,
+
+        /// drop these
+        #[arg(long, value_parser = parse_droppable, conflicts_with = "keep")]
+        drop: Vec<ClientEventKind>,
+
+        /// drop everything but these
+        #[arg(long, value_parser = parse_kind)]
+        keep: Vec<ClientEventKind>,
+    },
+
+    /// play several demos one after the other
+    Merge {
+        output: String,
+
+        #[arg(required = true)]
+        inputs: Vec<String>,
+    },
+
+    /// one player's view of an mvd2 as a regular demo
+    Pov {
+        input: String,
+        output: String,
+
+        /// the player's slot number or name
+        #[arg(short, long)]
+        player: String,
+    },
+}
+
+fn main() {
+    let args = Args::parse();
+
+    if let Err(e) = run(args.command) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(command: Command) -> Result<(), Q2ProtoError> {
+    match command {
+        Command::Cut {
+            input,
+            output,
+            start,
+            end,
+            start_frame,
+            end_frame,
+        } => {
+            let position = |secs: Option<f64>, frame: Option<u32>| {
+                secs.map(|s| DemoPosition::Time(Duration::from_secs_f64(s)))
+                    .or(frame.map(DemoPosition::Frame))
+            };
+            let range = CutRange {
+                start: position(start, start_frame),
+                end: position(end, end_frame),
+            };
+
+            let mut writer = DemoWriter::create(output)?;
+            cut_demo(&mut DemoReader::open(input)?, &mut writer, &range)?;
+            writer.finish()?;
+        }
+        Command::Filter {
+            input,
+            output,
+            drop,
+            keep,
+        } => {
+            let mut writer = DemoWriter::create(output)?;
+            filter_demo(&mut DemoReader::open(input)?, &mut writer, |evt| {
+                let kind = evt.kind();
+                if is_required(evt) {
+                    true
+                } else if !keep.is_empty() {
+                    keep.contains(&kind)
+                } else {
+                    !drop.contains(&kind)
+                }
+            })?;
+            writer.finish()?;
+        }
+        Command::Merge { output, inputs } => {
+            let mut readers = inputs
+                .iter()
+                .map(DemoReader::open)
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let mut writer = DemoWriter::create(output)?;
+            merge_demos(&mut readers, &mut writer)?;
+            writer.finish()?;
+        }
+        Command::Pov {
+            input,
+            output,
+            player,
+        } => {
+            let player = find_player(&input, &player)?;
+
+            let mut writer = DemoWriter::create(output)?;
+            extract_pov(&mut MvdReader::open(input)?, &mut writer, player)?;
+            writer.finish()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_kind(kind: &str) -> Result<ClientEventKind, String> {
+    Ok(match kind.to_ascii_lowercase().as_str() {
+        "print" => ClientEventKind::Print,
+        "stufftext" => ClientEventKind::StuffText,
+        "centerprint" => ClientEventKind::CenterPrint,
+        "configstring" => ClientEventKind::ConfigString,
+        "sound" => ClientEventKind::Sound,
+        "tempentity" => ClientEventKind::TempEntity,
+        "muzzleflash" => ClientEventKind::MuzzleFlash,
+        "muzzleflash2" => ClientEventKind::MuzzleFlash2,
+        "layout" => ClientEventKind::Layout,
+        "inventory" => ClientEventKind::Inventory,
+        "disconnect" => ClientEventKind::Disconnect,
+        "reconnect" => ClientEventKind::Reconnect,
+        _ => return Err(format!("unknown message kind {}", kind)),
+    })
+}
+
+// dropping these would leave a demo that doesn't load.
+fn parse_droppable(kind: &str) -> Result<ClientEventKind, String> {
+    let kind = parse_kind(kind)?;
+    if REQUIRED.contains(&kind) {
+        return Err(format!("{:?} is needed for the demo to play", kind).to_lowercase());
+    }
+    Ok(kind)
+}
+
+// the level itself, and the stufftext that gets the client to load it.
+fn is_required(evt: &ClientEvent) -> bool {
+    REQUIRED.contains(&evt.kind())
+        || matches!(evt, ClientEvent::StuffText(text) if text.starts_with(b"precache"))
+}
+
+// a slot number as it is, or the name of someone in the mvd's first message.
+fn find_player(input: &str, player: &str) -> Result<u8, Q2ProtoError> {
+    if let Ok(number) = player.parse() {
+        return Ok(number);
+    }
+
+    let mut reader = MvdReader::open(input)?;
+    reader.next_events()?;
+    let state = reader.state();
+
+    state
+        .players()
+        .into_iter()
+        .find(|n| {
+            state
+                .player_name(*n)
+                .is_some_and(|name| name.eq_ignore_ascii_case(player))
+        })
+        .ok_or_else(|| Q2ProtoError::MalformedResponse(format!("no player called {}", player)))
+}
@@ -0,0 +1,46 @@
+use clap::Parser;
+use q2_proto::master::DEFAULT_MASTER_PORT;
+use q2_proto::master_server::MasterServer;
+use std::process;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(author, version, about = "q2-master: a quake 2 master server")]
+struct Args {
+    /// address to listen on
+    #[arg(short, long, default_value = "0.0.0.0")]
+    bind: String,
+
+    /// port to listen on
+    #[arg(short, long, default_value_t = DEFAULT_MASTER_PORT)]
+    port: u16,
+
+    /// drop servers that haven't sent a heartbeat in this many seconds
+    #[arg(short, long, default_value_t = 900)]
+    expire: u64,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let mut master = match MasterServer::new(&args.bind, args.port) {
+        Ok(master) => master,
+        Err(e) => {
+            eprintln!("couldn't start master: {}", e);
+            process::exit(1);
+        }
+    };
+    master.set_expire_after(Duration::from_secs(args.expire));
+
+    println!("listening on {}:{}", args.bind, args.port);
+    loop {
+        match master.poll() {
+            Ok(Some((peer, e))) => eprintln!("{}: {}", peer, e),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("master stopped: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
@@ -99,25 +99,28 @@ fn run_monitor(args: &Args) -> bool {
 
         let addr = format!("127.0.0.1:{}", args.port);
         let client = Q2ProtoClient::new(&addr, "127.0.0.1", 0, "q2-servmon");
-        if let Some(cl) = client {
-            cl.set_read_timeout(Duration::from_secs(args.status_timeout as u64))
-                .expect("couldn't set read timeout on status socket");
+        match client {
+            Ok(cl) => {
+                cl.set_read_timeout(Duration::from_secs(args.status_timeout as u64))
+                    .expect("couldn't set read timeout on status socket");
 
-            loop {
-                thread::sleep(Duration::from_secs(args.status_interval as u64));
-                if cl.status().is_none() {
-                    try_kill_child();
-                    eprintln!("server is down. exiting check loop.");
-                    return true
+                loop {
+                    thread::sleep(Duration::from_secs(args.status_interval as u64));
+                    if let Err(e) = cl.status() {
+                        try_kill_child();
+                        eprintln!("server is down ({}). exiting check loop.", e);
+                        return true
+                    }
                 }
             }
-        } else {
-            eprintln!("failed to create client");
-            return false
+            Err(e) => {
+                eprintln!("failed to create client: {}", e);
+                return false
+            }
         }
 
     } else if let Err(e) = spawned_child {
-        eprintln!("failed to spawn child process for monitoring: {}", e.to_string());
+        eprintln!("failed to spawn child process for monitoring: {}", e);
         return false
     }
     
@@ -0,0 +1,437 @@
+use super::chat::say_command;
+use super::connection::{ClientTimeouts, ConnectionState, NegotiateOptions};
+use super::error::Q2ProtoError;
+use super::netchan::RateLimit;
+use super::objects::DeltaEntity;
+use super::rcon::{
+    parse_dumpuser, parse_rcon_reply, parse_serverinfo, parse_status, rcon_packet, RconStatus,
+    RCON_MORE_WAIT,
+};
+use super::session::{connectionless_payload, oob_packet, parse_challenge, ClientSession};
+use super::status::{ServerInfo, ServerStatus};
+use super::subscription::Subscription;
+use super::user_info::UserInfo;
+use super::{
+    Challenge, ClientEvent, ClientEventKind, ProtocolVersion, DEFAULT_READ_TIMEOUT,
+    MAX_WRITEABLE_SIZE,
+};
+use futures_util::stream::{self, Stream};
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::timeout_at;
+
+// Same thing as Q2ProtoClient, but on a tokio socket. Both drive a ClientSession.
+pub struct AsyncQ2ProtoClient {
+    socket: UdpSocket,
+    server_address: SocketAddr,
+    read_timeout: Duration,
+    session: ClientSession,
+}
+
+impl AsyncQ2ProtoClient {
+    pub async fn new(
+        server: &str,
+        bind_addr: &str,
+        bind_port: u16,
+        version: &str,
+    ) -> Result<AsyncQ2ProtoClient, Q2ProtoError> {
+        let socket = UdpSocket::bind(format!("{}:{}", bind_addr, bind_port))
+            .await
+            .map_err(Q2ProtoError::Bind)?;
+        let server_address = lookup_host(server)
+            .await?
+            .next()
+            .ok_or_else(|| Q2ProtoError::Io(std::io::Error::from(ErrorKind::AddrNotAvailable)))?;
+
+        Ok(AsyncQ2ProtoClient {
+            socket,
+            server_address,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            session: ClientSession::new(bind_port, version),
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.session.state().has_netchan()
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.session.state()
+    }
+
+    pub fn timeouts(&self) -> ClientTimeouts {
+        self.session.timeouts()
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: ClientTimeouts) {
+        self.session.set_timeouts(timeouts);
+    }
+
+    // overrides the cadence `connect` picked up from the userinfo `cl_maxfps`.
+    pub fn set_rate_limit(&mut self, rate: RateLimit) {
+        self.session.set_rate_limit(rate);
+    }
+
+    pub fn rate_limit(&self) -> RateLimit {
+        self.session.rate_limit()
+    }
+
+    // how long `pump` and the OOB queries wait for a packet.
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = timeout;
+    }
+
+    async fn oob_print(&self, msg: &[u8]) -> std::io::Result<usize> {
+        self.socket
+            .send_to(&oob_packet(msg), self.server_address)
+            .await
+    }
+
+    async fn recv_connectionless_bytes(&self) -> Result<Vec<u8>, Q2ProtoError> {
+        self.recv_connectionless_within(self.read_timeout).await
+    }
+
+    // skips netchan packets and strangers until the answer comes, like the blocking one.
+    async fn recv_connectionless_within(&self, wait: Duration) -> Result<Vec<u8>, Q2ProtoError> {
+        let mut buf = [0u8; 1500];
+        let deadline = Instant::now() + wait;
+
+        loop {
+            let (recv_bytes, addr) = timeout_at(deadline.into(), self.socket.recv_from(&mut buf))
+                .await
+                .map_err(|_| Q2ProtoError::Timeout)??;
+            if addr != self.server_address {
+                continue; // not our server...
+            }
+            if let Some(payload) = connectionless_payload(&buf[..recv_bytes]) {
+                return Ok(payload.to_vec());
+            }
+        }
+    }
+
+    async fn recv_connectionless(&self) -> Result<String, Q2ProtoError> {
+        String::from_utf8(self.recv_connectionless_bytes().await?).map_err(|e| {
+            Q2ProtoError::MalformedResponse(String::from_utf8_lossy(e.as_bytes()).into_owned())
+        })
+    }
+
+    pub async fn status(&self) -> Result<ServerStatus, Q2ProtoError> {
+        self.oob_print(b"status").await?;
+        ServerStatus::parse(&self.recv_connectionless_bytes().await?)
+    }
+
+    // lighter than status, just hostname, map and player count.
+    pub async fn info(&self, proto: ProtocolVersion) -> Result<ServerInfo, Q2ProtoError> {
+        self.oob_print(format!("info {}", proto as i32).as_bytes())
+            .await?;
+        ServerInfo::parse(&self.recv_connectionless_bytes().await?)
+    }
+
+    pub async fn challenge(&self) -> Result<Challenge, Q2ProtoError> {
+        self.oob_print(b"getchallenge").await?;
+
+        let str = self.recv_connectionless().await?;
+        parse_challenge(&str)
+    }
+
+    // runs a console command on the server and returns everything it printed.
+    pub async fn rcon(&self, password: &str, cmd: &str) -> Result<String, Q2ProtoError> {
+        self.socket
+            .send_to(&rcon_packet(password, cmd), self.server_address)
+            .await?;
+
+        let mut reply = parse_rcon_reply(&self.recv_connectionless_bytes().await?)?;
+        loop {
+            match self.recv_connectionless_within(RCON_MORE_WAIT).await {
+                Ok(payload) => reply.push_str(&parse_rcon_reply(&payload)?),
+                Err(Q2ProtoError::Timeout) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(reply)
+    }
+
+    // who's on, as the server console sees it.
+    pub async fn rcon_status(&self, password: &str) -> Result<RconStatus, Q2ProtoError> {
+        Ok(parse_status(&self.rcon(password, "status").await?))
+    }
+
+    pub async fn kick(&self, password: &str, slot: u32) -> Result<String, Q2ProtoError> {
+        self.rcon(password, &format!("kick {}", slot)).await
+    }
+
+    pub async fn changemap(&self, password: &str, map: &str) -> Result<String, Q2ProtoError> {
+        self.rcon(password, &format!("map {}", map)).await
+    }
+
+    pub async fn serverinfo(
+        &self,
+        password: &str,
+    ) -> Result<HashMap<String, String>, Q2ProtoError> {
+        Ok(parse_serverinfo(&self.rcon(password, "serverinfo").await?))
+    }
+
+    // the userinfo of whoever is in that slot.
+    pub async fn dumpuser(&self, password: &str, slot: u32) -> Result<UserInfo, Q2ProtoError> {
+        parse_dumpuser(&self.rcon(password, &format!("dumpuser {}", slot)).await?)
+    }
+
+    pub fn send_command(&mut self, cmd: &str) -> Result<(), Q2ProtoError> {
+        self.session.send_command(cmd)
+    }
+
+    pub fn say(&mut self, text: &str) -> Result<(), Q2ProtoError> {
+        self.session
+            .send_command(&say_command(&self.session.name(), text, false))
+    }
+
+    pub fn say_team(&mut self, text: &str) -> Result<(), Q2ProtoError> {
+        self.session
+            .send_command(&say_command(&self.session.name(), text, true))
+    }
+
+    // connect with a challenge we already have. resolves once the server lets us in or we give up.
+    pub async fn connect(
+        &mut self,
+        challenge: Challenge,
+        proto: ProtocolVersion,
+        userinfo: UserInfo,
+    ) -> Result<(), Q2ProtoError> {
+        self.socket.connect(self.server_address).await?;
+        self.session
+            .start_connect(challenge, proto, userinfo, Instant::now())?;
+        self.flush().await?;
+
+        self.wait_for_handshake().await
+    }
+
+    // do the whole process to get into a server.
+    pub async fn negotiate(
+        &mut self,
+        proto: ProtocolVersion,
+        userinfo: UserInfo,
+    ) -> Result<(), Q2ProtoError> {
+        self.negotiate_with(proto, userinfo, &NegotiateOptions::default())
+            .await
+    }
+
+    // same as negotiate, for locked servers. a wrong password comes back as
+    // Rejected(RejectReason::BadPassword, ..).
+    pub async fn negotiate_with(
+        &mut self,
+        proto: ProtocolVersion,
+        mut userinfo: UserInfo,
+        options: &NegotiateOptions,
+    ) -> Result<(), Q2ProtoError> {
+        options.apply(&mut userinfo);
+
+        self.socket.connect(self.server_address).await?;
+        self.session
+            .start_challenge(proto, userinfo, Instant::now())?;
+        self.flush().await?;
+
+        self.wait_for_handshake().await
+    }
+
+    async fn wait_for_handshake(&mut self) -> Result<(), Q2ProtoError> {
+        // whatever comes in meanwhile stays queued for the next pump.
+        while self.session.state().is_handshaking() {
+            self.pump_packets().await?;
+        }
+
+        match self.session.take_handshake_error() {
+            Some(e) => Err(e),
+            None if self.session.state().has_netchan() => Ok(()),
+            None => Err(Q2ProtoError::NotConnected),
+        }
+    }
+
+    pub fn configstring(&self, index: u16) -> Option<&[u8]> {
+        self.session.configstring(index)
+    }
+
+    pub fn configstrings(&self) -> &HashMap<u16, Vec<u8>> {
+        self.session.configstrings()
+    }
+
+    pub fn baseline(&self, number: i16) -> Option<&DeltaEntity> {
+        self.session.baseline(number)
+    }
+
+    pub fn baselines(&self) -> &HashMap<i16, DeltaEntity> {
+        self.session.baselines()
+    }
+
+    // tell the server we're leaving and forget about the connection.
+    pub async fn disconnect(&mut self) {
+        self.session.disconnect(Instant::now());
+        let _ = self.flush().await;
+    }
+
+    // the listener stays registered until the returned handle is dropped.
+    pub fn subscribe<F>(&mut self, kind: ClientEventKind, callback: F) -> Subscription
+    where
+        F: FnMut(&ClientEvent) + Send + 'static,
+    {
+        self.session.subscribe(Some(kind), Box::new(callback))
+    }
+
+    pub fn subscribe_all<F>(&mut self, callback: F) -> Subscription
+    where
+        F: FnMut(&ClientEvent) + Send + 'static,
+    {
+        self.session.subscribe(None, Box::new(callback))
+    }
+
+    // every event also gets sent here, so another task or thread can consume them.
+    pub fn set_event_sender(&mut self, sender: Sender<ClientEvent>) {
+        self.session.set_event_sender(Some(sender));
+    }
+
+    pub fn clear_event_sender(&mut self) {
+        self.session.set_event_sender(None);
+    }
+
+    // waits for whatever the server sent and returns the events it produced, including
+    // the ones that came in during negotiate.
+    pub async fn pump(&mut self) -> Result<Vec<ClientEvent>, Q2ProtoError> {
+        self.pump_packets().await?;
+        Ok(self.session.take_events())
+    }
+
+    // keeps pumping and yields events one by one. stops at the first error, or without one
+    // once the connection is gone.
+    pub fn event_stream(&mut self) -> impl Stream<Item = Result<ClientEvent, Q2ProtoError>> + '_ {
+        stream::unfold(
+            (self, VecDeque::new(), false),
+            |(client, mut pending, mut done)| async move {
+                loop {
+                    if let Some(evt) = pending.pop_front() {
+                        return Some((Ok(evt), (client, pending, done)));
+                    }
+                    if done {
+                        return None;
+                    }
+
+                    match client.pump().await {
+                        Ok(events) => pending.extend(events),
+                        Err(Q2ProtoError::NotConnected) => done = true,
+                        Err(e) => return Some((Err(e), (client, pending, true))),
+                    }
+                }
+            },
+        )
+    }
+
+    async fn pump_packets(&mut self) -> Result<(), Q2ProtoError> {
+        if !self.session.is_live() {
+            return Err(Q2ProtoError::NotConnected);
+        }
+
+        let mut buf = [0u8; MAX_WRITEABLE_SIZE];
+
+        // don't sleep through a resend or a keepalive.
+        let mut deadline = Instant::now() + self.read_timeout;
+        if let Some(session_deadline) = self.session.poll_timeout() {
+            deadline = deadline.min(session_deadline);
+        }
+
+        // wait for the first one, then take whatever else is already there. if nothing
+        // comes in, there's still the timeout to handle.
+        if let Ok(res) = timeout_at(deadline.into(), self.socket.recv(&mut buf)).await {
+            self.handle_datagram(&buf[..res?]).await?;
+
+            loop {
+                match self.socket.try_recv(&mut buf) {
+                    Ok(res) => self.handle_datagram(&buf[..res]).await?,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        // this is where a dead server gets noticed.
+        self.session.handle_timeout(Instant::now());
+        self.flush().await
+    }
+
+    async fn handle_datagram(&mut self, data: &[u8]) -> Result<(), Q2ProtoError> {
+        let now = Instant::now();
+        self.session.handle_datagram(data, now)?;
+        self.session.handle_timeout(now);
+        self.flush().await
+    }
+
+    async fn flush(&mut self) -> Result<(), Q2ProtoError> {
+        while let Some(data) = self.session.poll_transmit() {
+            self.socket.send_to(&data, self.server_address).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // answers `status` and `getchallenge` once each, after a stray from somebody else.
+    fn stand_in() -> (String, thread::JoinHandle<()>) {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+
+        let handle = thread::spawn(move || {
+            let stranger = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let mut buf = [0u8; 1500];
+            for _ in 0..2 {
+                let (len, client) = server.recv_from(&mut buf).unwrap();
+                let reply: &[u8] = match connectionless_payload(&buf[..len]).unwrap() {
+                    b"status" => b"print\n\\hostname\\real\n",
+                    b"getchallenge" => b"challenge 5 p=34",
+                    other => panic!("didn't expect {:?}", other),
+                };
+                stranger
+                    .send_to(&oob_packet(b"print\n\\hostname\\fake\n"), client)
+                    .unwrap();
+                server.send_to(&oob_packet(reply), client).unwrap();
+            }
+        });
+
+        (addr, handle)
+    }
+
+    #[tokio::test]
+    async fn status_and_challenge() {
+        let (addr, server) = stand_in();
+        let client = AsyncQ2ProtoClient::new(&addr, "127.0.0.1", 0, "test")
+            .await
+            .unwrap();
+
+        let status = client.status().await.unwrap();
+        assert_eq!(status.hostname(), Some("real"));
+
+        let challenge = client.challenge().await.unwrap();
+        assert_eq!(challenge.ch_value, "5");
+        assert_eq!(challenge.protocols, "34");
+
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn event_stream_ends_without_a_connection() {
+        use futures_util::StreamExt;
+
+        let mut client = AsyncQ2ProtoClient::new("127.0.0.1:27910", "127.0.0.1", 0, "test")
+            .await
+            .unwrap();
+        let events = client.event_stream();
+        futures_util::pin_mut!(events);
+        assert!(events.next().await.is_none());
+    }
+}
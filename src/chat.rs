@@ -0,0 +1,162 @@
+use super::text::{decode, encode};
+
+// the game cuts "name: text" off at this many bytes (cmd_say_f), so how much of the text
+// survives depends on the name.
+pub const MAX_SAY_LENGTH: usize = 150;
+
+// someone said something, from a PrintLevel::CHAT print.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub sender: String,
+    // said with say_team, only their team saw it.
+    pub team: bool,
+    pub text: String,
+}
+
+// the game prints "name: text" for say and "(name): text" for say_team. names can have
+// ": " in them too, so the longest known player that fits is the sender. without one we
+// split at the first ": ", which is also what the server console's own "console: " gets.
+pub fn parse_chat(text: &[u8], players: &[String]) -> Option<ChatMessage> {
+    let text = decode(text);
+    let text = text.trim_end_matches('\n');
+
+    let mut players: Vec<&String> = players.iter().collect();
+    players.sort_by_key(|p| std::cmp::Reverse(p.len()));
+
+    for player in players {
+        if let Some(said) = text
+            .strip_prefix('(')
+            .and_then(|t| t.strip_prefix(player.as_str()))
+            .and_then(|t| t.strip_prefix("): "))
+        {
+            return Some(ChatMessage {
+                sender: player.clone(),
+                team: true,
+                text: said.to_string(),
+            });
+        }
+
+        if let Some(said) = text
+            .strip_prefix(player.as_str())
+            .and_then(|t| t.strip_prefix(": "))
+        {
+            return Some(ChatMessage {
+                sender: player.clone(),
+                team: false,
+                text: said.to_string(),
+            });
+        }
+    }
+
+    let (sender, said) = text.split_once(": ")?;
+    let team = sender.starts_with('(') && sender.ends_with(')') && sender.len() > 1;
+    let sender = if team {
+        &sender[1..sender.len() - 1]
+    } else {
+        sender
+    };
+
+    Some(ChatMessage {
+        sender: sender.to_string(),
+        team,
+        text: said.to_string(),
+    })
+}
+
+// the stringcmd for `name` saying `text`, in the q2 charset. there's no escaping a quote in
+// a q2 command line, so they become single quotes, and a newline would end the command
+// early. the text is cut to what the game will keep once it puts the name in front.
+pub fn say_command(name: &str, text: &str, team: bool) -> String {
+    // "name: " or "(name): "
+    let prefix = encode(name).len() + if team { 4 } else { 2 };
+    let said: String = encode(text)
+        .into_iter()
+        .take(MAX_SAY_LENGTH.saturating_sub(prefix))
+        .map(|b| match b {
+            b'"' => '\'',
+            b'\n' => ' ',
+            b => char::from(b),
+        })
+        .collect();
+
+    // quoted, so the server doesn't expand $cvars or split it at ;
+    let cmd = if team { "say_team" } else { "say" };
+    format!("{} \"{}\"", cmd, said)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(sender: &str, team: bool, text: &str) -> Option<ChatMessage> {
+        Some(ChatMessage {
+            sender: sender.to_string(),
+            team,
+            text: text.to_string(),
+        })
+    }
+
+    #[test]
+    fn longest_known_name_wins() {
+        let players = vec!["Bob".to_string(), "Bob: hi".to_string()];
+
+        assert_eq!(
+            parse_chat(b"Bob: hi: there\n", &players),
+            chat("Bob: hi", false, "there")
+        );
+        assert_eq!(
+            parse_chat(b"(Bob: hi): there\n", &players),
+            chat("Bob: hi", true, "there")
+        );
+        assert_eq!(
+            parse_chat(b"Bob: hello: there\n", &players),
+            chat("Bob", false, "hello: there")
+        );
+    }
+
+    #[test]
+    fn unknown_senders_split_at_the_first_colon() {
+        assert_eq!(
+            parse_chat(b"console: map q2dm1: now\n", &[]),
+            chat("console", false, "map q2dm1: now")
+        );
+        assert_eq!(
+            parse_chat(b"(Alice): go: left\n", &["Bob".to_string()]),
+            chat("Alice", true, "go: left")
+        );
+        assert_eq!(parse_chat(b"Alice entered the game\n", &[]), None);
+    }
+
+    #[test]
+    fn say_escapes_and_truncates() {
+        assert_eq!(
+            say_command("Bob", "he said \"hi\"\nthen left", false),
+            "say \"he said 'hi' then left\""
+        );
+        assert_eq!(say_command("Bob", "gg", true), "say_team \"gg\"");
+
+        // "Bob: " and "(Bob): " take their share of the limit.
+        let said = say_command("Bob", &"\"".repeat(MAX_SAY_LENGTH), false);
+        assert_eq!(said, format!("say \"{}\"", "'".repeat(MAX_SAY_LENGTH - 5)));
+        let said = say_command("Bob", &"x".repeat(MAX_SAY_LENGTH), true);
+        assert_eq!(
+            said,
+            format!("say_team \"{}\"", "x".repeat(MAX_SAY_LENGTH - 7))
+        );
+    }
+
+    #[test]
+    fn truncation_keeps_whole_characters() {
+        // one byte per character in the q2 charset, so the cut can't land inside one.
+        let text = "•─é←".repeat(MAX_SAY_LENGTH);
+        let said = say_command("", &text, false);
+        let said = said
+            .strip_prefix("say \"")
+            .and_then(|s| s.strip_suffix('"'))
+            .unwrap();
+
+        assert_eq!(said.len(), MAX_SAY_LENGTH - 2);
+        let expected: String = "•─?←".chars().cycle().take(MAX_SAY_LENGTH - 2).collect();
+        assert_eq!(decode(said.as_bytes()), expected);
+    }
+}
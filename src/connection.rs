@@ -0,0 +1,137 @@
+use super::user_info::UserInfo;
+use std::time::Duration;
+
+// cl_timeout's stock value.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+// CL_CheckForResend waits this long between getchallenge/connect attempts.
+pub const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(3);
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ConnectionState {
+    // not talking to anyone.
+    Disconnected,
+    // sent getchallenge, waiting for the `challenge` reply.
+    Challenging,
+    // sent connect, waiting for `client_connect`.
+    Connecting,
+    // netchan is up and `new` went out. waiting for serverdata.
+    Connected,
+    // got serverdata, configstrings and baselines are coming in until precache.
+    Loading,
+    // sent `begin`. we're in the game.
+    Active,
+    // the server dropped us or went quiet. nothing else will come through this connection.
+    Zombie,
+}
+
+impl ConnectionState {
+    // still trading connectionless packets to get a netchan going.
+    pub fn is_handshaking(&self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Challenging | ConnectionState::Connecting
+        )
+    }
+
+    // the netchan is up and usable.
+    pub fn has_netchan(&self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Connected | ConnectionState::Loading | ConnectionState::Active
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientTimeouts {
+    // no packets from the server for this long means it's gone.
+    pub timeout: Duration,
+    // resend getchallenge/connect if nothing came back within this time.
+    pub retry_interval: Duration,
+    // give up on the handshake after this many resends.
+    pub max_retries: u32,
+}
+
+impl Default for ClientTimeouts {
+    fn default() -> Self {
+        ClientTimeouts {
+            timeout: DEFAULT_TIMEOUT,
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+            max_retries: DEFAULT_MAX_RETRIES,
+        }
+    }
+}
+
+// extra things negotiate can put in the userinfo for us.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NegotiateOptions {
+    // the server's `password` cvar.
+    pub password: Option<String>,
+    // join as a spectator. this is checked against the game's `spectator_password`.
+    pub spectator_password: Option<String>,
+}
+
+impl NegotiateOptions {
+    pub fn with_password(password: &str) -> NegotiateOptions {
+        NegotiateOptions {
+            password: Some(password.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_spectator_password(password: &str) -> NegotiateOptions {
+        NegotiateOptions {
+            spectator_password: Some(password.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn apply(&self, userinfo: &mut UserInfo) {
+        if let Some(password) = &self.password {
+            userinfo
+                .keys
+                .insert(String::from("password"), password.clone());
+        }
+
+        // the game dll compares the `spectator` key with spectator_password.
+        if let Some(password) = &self.spectator_password {
+            userinfo
+                .keys
+                .insert(String::from("spectator"), password.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RejectReason;
+
+    #[test]
+    fn options_go_in_the_userinfo() {
+        let mut userinfo = UserInfo::new();
+        userinfo.keys.insert("name".to_string(), "test".to_string());
+
+        let options = NegotiateOptions {
+            password: Some("secret".to_string()),
+            spectator_password: Some("watch".to_string()),
+        };
+        options.apply(&mut userinfo);
+        assert_eq!(userinfo.keys["password"], "secret");
+        assert_eq!(userinfo.keys["spectator"], "watch");
+        assert_eq!(userinfo.keys["name"], "test");
+
+        // nothing set, nothing added.
+        let mut userinfo = UserInfo::new();
+        NegotiateOptions::default().apply(&mut userinfo);
+        assert!(userinfo.keys.is_empty());
+    }
+
+    #[test]
+    fn wrong_passwords() {
+        for msg in ["Bad password.", "Spectator password required or incorrect."] {
+            assert_eq!(RejectReason::from_message(msg), RejectReason::BadPassword);
+        }
+    }
+}
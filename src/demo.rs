@@ -0,0 +1,270 @@
+use super::error::Q2ProtoError;
+use super::message::{parse_message, write_event};
+use super::objects::{
+    write_baseline, write_configstring, write_serverdata, write_stufftext, DeltaEntity,
+    ServerDataMessage,
+};
+use super::ClientEvent;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+// no real message comes close to this. anything bigger is a broken file.
+pub const MAX_DEMO_BLOCK: usize = 0x10000;
+// what a .dm2 ends with in place of a block length.
+pub const DEMO_END: i32 = -1;
+// the biggest block old clients will play back. cl_record keeps the header under this too.
+pub const MAX_MSGLEN: usize = 1400;
+
+// reads a .dm2: server messages as the client got them, each one prefixed with its length.
+pub struct DemoReader<R: Read> {
+    reader: R,
+    pending: VecDeque<ClientEvent>,
+    done: bool,
+}
+
+impl DemoReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Q2ProtoError> {
+        Ok(DemoReader::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: Read> DemoReader<R> {
+    pub fn new(reader: R) -> Self {
+        DemoReader {
+            reader,
+            pending: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    // the next message as it is in the file. None once the demo is over.
+    pub fn next_block(&mut self) -> Result<Option<Vec<u8>>, Q2ProtoError> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let len = match self.reader.read_i32::<LittleEndian>() {
+            Ok(len) => len,
+            // some demos just stop without the end marker.
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => DEMO_END,
+            Err(e) => return Err(Q2ProtoError::Io(e)),
+        };
+        if len == DEMO_END {
+            self.done = true;
+            return Ok(None);
+        }
+        if len < 0 || len as usize > MAX_DEMO_BLOCK {
+            self.done = true;
+            return Err(Q2ProtoError::MalformedResponse(format!(
+                "demo block of {} bytes",
+                len
+            )));
+        }
+
+        let mut block = vec![0u8; len as usize];
+        self.reader
+            .read_exact(&mut block)
+            .map_err(Q2ProtoError::Io)?;
+
+        Ok(Some(block))
+    }
+
+    // all the events in the next message. None once the demo is over.
+    pub fn next_events(&mut self) -> Result<Option<Vec<ClientEvent>>, Q2ProtoError> {
+        match self.next_block()? {
+            Some(block) => Ok(Some(parse_message(&block)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+// stops at the first error.
+impl<R: Read> Iterator for DemoReader<R> {
+    type Item = Result<ClientEvent, Q2ProtoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(evt) = self.pending.pop_front() {
+                return Some(Ok(evt));
+            }
+
+            match self.next_events() {
+                Ok(Some(events)) => self.pending.extend(events),
+                Ok(None) => return None,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// writes a .dm2 one message at a time.
+pub struct DemoWriter<W: Write> {
+    writer: W,
+}
+
+impl DemoWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Q2ProtoError> {
+        Ok(DemoWriter::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> DemoWriter<W> {
+    pub fn new(writer: W) -> Self {
+        DemoWriter { writer }
+    }
+
+    pub fn write_block(&mut self, block: &[u8]) -> Result<(), Q2ProtoError> {
+        self.writer
+            .write_i32::<LittleEndian>(block.len() as i32)
+            .map_err(Q2ProtoError::Io)?;
+        self.writer.write_all(block).map_err(Q2ProtoError::Io)
+    }
+
+    // what cl_record puts at the start, so the demo can start in the middle of a level:
+    // serverdata, every configstring, every baseline and a `precache`.
+    pub fn write_header(
+        &mut self,
+        serverdata: &ServerDataMessage,
+        configstrings: &HashMap<u16, Vec<u8>>,
+        baselines: &HashMap<i16, DeltaEntity>,
+    ) -> Result<(), Q2ProtoError> {
+        let mut serverdata = serverdata.clone();
+        serverdata.attract_loop = 1; // demos are always attract loops
+
+        let mut block = Vec::with_capacity(MAX_MSGLEN);
+        write_serverdata(&mut block, &serverdata);
+
+        let mut indexes: Vec<&u16> = configstrings.keys().collect();
+        indexes.sort();
+        for index in indexes {
+            let mut msg = Vec::new();
+            write_configstring(&mut msg, *index, &configstrings[index]);
+            self.append_block(&mut block, &msg)?;
+        }
+
+        let mut numbers: Vec<&i16> = baselines.keys().collect();
+        numbers.sort();
+        for number in numbers {
+            let mut msg = Vec::new();
+            write_baseline(&mut msg, &baselines[number]);
+            self.append_block(&mut block, &msg)?;
+        }
+
+        let mut msg = Vec::new();
+        write_stufftext(&mut msg, b"precache\n");
+        self.append_block(&mut block, &msg)?;
+
+        self.write_block(&block)
+    }
+
+    // packs the events into as few blocks as will fit. an event never gets split up.
+    pub fn write_events(&mut self, events: &[ClientEvent]) -> Result<(), Q2ProtoError> {
+        let mut block = Vec::with_capacity(MAX_MSGLEN);
+        for evt in events {
+            let mut msg = Vec::new();
+            write_event(&mut msg, evt);
+            self.append_block(&mut block, &msg)?;
+        }
+
+        if !block.is_empty() {
+            self.write_block(&block)?;
+        }
+        Ok(())
+    }
+
+    fn append_block(&mut self, block: &mut Vec<u8>, msg: &[u8]) -> Result<(), Q2ProtoError> {
+        if !block.is_empty() && block.len() + msg.len() > MAX_MSGLEN {
+            self.write_block(block)?;
+            block.clear();
+        }
+
+        block.extend_from_slice(msg);
+        Ok(())
+    }
+
+    // writes the end marker. the demo isn't complete without it.
+    pub fn finish(mut self) -> Result<W, Q2ProtoError> {
+        self.writer
+            .write_i32::<LittleEndian>(DEMO_END)
+            .map_err(Q2ProtoError::Io)?;
+        self.writer.flush().map_err(Q2ProtoError::Io)?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::{write_print, PrintLevel};
+
+    fn block(demo: &mut Vec<u8>, msg: &[u8]) {
+        demo.extend_from_slice(&(msg.len() as i32).to_le_bytes());
+        demo.extend_from_slice(msg);
+    }
+
+    fn print(text: &[u8]) -> Vec<u8> {
+        let mut msg = Vec::new();
+        write_print(&mut msg, PrintLevel::HIGH, text);
+        msg
+    }
+
+    #[test]
+    fn reads_blocks_until_the_end_marker() {
+        let mut demo = Vec::new();
+        let mut two = print(b"two\n");
+        two.extend(print(b"three\n"));
+        block(&mut demo, &print(b"one\n"));
+        block(&mut demo, &two);
+        demo.extend_from_slice(&DEMO_END.to_le_bytes());
+        // nothing after the marker gets read.
+        block(&mut demo, &print(b"after\n"));
+
+        let events: Vec<ClientEvent> = DemoReader::new(&demo[..])
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                ClientEvent::Print(PrintLevel::HIGH, b"one\n".to_vec()),
+                ClientEvent::Print(PrintLevel::HIGH, b"two\n".to_vec()),
+                ClientEvent::Print(PrintLevel::HIGH, b"three\n".to_vec()),
+            ]
+        );
+
+        // without the marker it just ends.
+        let mut demo = Vec::new();
+        block(&mut demo, &print(b"one\n"));
+        assert_eq!(DemoReader::new(&demo[..]).count(), 1);
+    }
+
+    #[test]
+    fn truncated_blocks() {
+        let mut demo = Vec::new();
+        block(&mut demo, &print(b"one\n"));
+        let cut = print(b"this one is cut short\n");
+        demo.extend_from_slice(&(cut.len() as i32).to_le_bytes());
+        demo.extend_from_slice(&cut[..5]);
+
+        let mut reader = DemoReader::new(&demo[..]);
+        assert!(matches!(reader.next(), Some(Ok(ClientEvent::Print(..)))));
+        assert!(matches!(reader.next(), Some(Err(Q2ProtoError::Io(_)))));
+        assert!(reader.next().is_none());
+
+        // a length no block could have.
+        let mut demo = Vec::new();
+        demo.extend_from_slice(&(MAX_DEMO_BLOCK as i32 + 1).to_le_bytes());
+        let mut reader = DemoReader::new(&demo[..]);
+        assert!(matches!(
+            reader.next_block(),
+            Err(Q2ProtoError::MalformedResponse(_))
+        ));
+        assert!(reader.next_block().unwrap().is_none());
+    }
+}
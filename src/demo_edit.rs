@@ -0,0 +1,643 @@
+use super::demo::{DemoReader, DemoWriter};
+use super::error::Q2ProtoError;
+use super::message::parse_message;
+use super::mvd::{MvdEvent, MvdReader, MvdState};
+use super::objects::{
+    DeltaEntity, FrameMessage, PlayerState, ProtocolInfo, ServerDataMessage, CS_NAME,
+};
+use super::{ClientEvent, ClientEventKind, ProtocolVersion};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
+use std::time::Duration;
+
+// the server runs at 10hz, so that's how long a frame is.
+pub const FRAME_TIME: Duration = Duration::from_millis(100);
+// how many old frames the client keeps to delta from.
+const UPDATE_BACKUP: usize = 16;
+// every area visible. without map data we can't tell which ones the player sees.
+const ALL_AREAS: [u8; 32] = [0xff; 32];
+
+// where in a demo, counting frames from the start of it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DemoPosition {
+    Frame(u32),
+    Time(Duration),
+}
+
+impl DemoPosition {
+    fn frame(&self) -> u32 {
+        match self {
+            DemoPosition::Frame(frame) => *frame,
+            DemoPosition::Time(time) => (time.as_millis() / FRAME_TIME.as_millis()) as u32,
+        }
+    }
+}
+
+// both ends are kept. no start means from the beginning, no end means to the end.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CutRange {
+    pub start: Option<DemoPosition>,
+    pub end: Option<DemoPosition>,
+}
+
+// a frame with its deltas applied: the whole player state and every entity in it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FullFrame {
+    pub server_frame: i32,
+    pub area_bits: Vec<u8>,
+    pub player_state: PlayerState,
+    pub entities: BTreeMap<i16, DeltaEntity>,
+}
+
+// what a client playing the demo back would know at this point.
+#[derive(Default)]
+pub struct DemoState {
+    serverdata: Option<ServerDataMessage>,
+    configstrings: HashMap<u16, Vec<u8>>,
+    baselines: HashMap<i16, DeltaEntity>,
+    frames: VecDeque<FullFrame>,
+}
+
+impl DemoState {
+    pub fn new() -> DemoState {
+        DemoState::default()
+    }
+
+    pub fn serverdata(&self) -> Option<&ServerDataMessage> {
+        self.serverdata.as_ref()
+    }
+
+    pub fn configstrings(&self) -> &HashMap<u16, Vec<u8>> {
+        &self.configstrings
+    }
+
+    pub fn baselines(&self) -> &HashMap<i16, DeltaEntity> {
+        &self.baselines
+    }
+
+    pub fn frame(&self, server_frame: i32) -> Option<&FullFrame> {
+        self.frames.iter().find(|f| f.server_frame == server_frame)
+    }
+
+    pub fn last_frame(&self) -> Option<&FullFrame> {
+        self.frames.back()
+    }
+
+    pub fn update(&mut self, evt: &ClientEvent) {
+        match evt {
+            ClientEvent::ServerData(serverdata) => {
+                self.serverdata = Some(serverdata.clone());
+                self.configstrings.clear();
+                self.baselines.clear();
+                self.frames.clear();
+            }
+            ClientEvent::ConfigString(index, value) => {
+                self.configstrings.insert(*index, value.clone());
+            }
+            ClientEvent::DeltaEntity(ent) => {
+                self.baselines.insert(ent.number, ent.clone());
+            }
+            ClientEvent::Frame(frame) => self.apply_frame(frame),
+            _ => {}
+        }
+    }
+
+    // cl_parseframe and cl_parsepacketentities, more or less.
+    fn apply_frame(&mut self, frame: &FrameMessage) {
+        let old = match frame.delta_frame {
+            -1 => None,
+            // a delta from a frame we never saw can't be rebuilt. the client drops those too.
+            delta_frame => match self.frame(delta_frame) {
+                Some(old) => Some(old),
+                None => return,
+            },
+        };
+
+        let mut full = FullFrame {
+            server_frame: frame.server_frame,
+            area_bits: frame.area_bits.clone(),
+            player_state: old.map(|f| f.player_state.clone()).unwrap_or_default(),
+            entities: old.map(|f| f.entities.clone()).unwrap_or_default(),
+        };
+        full.player_state.apply(&frame.player_state);
+
+        // events only last the one frame.
+        for ent in full.entities.values_mut() {
+            ent.event = 0;
+        }
+        for delta in &frame.entities {
+            if delta.remove {
+                full.entities.remove(&delta.number);
+                continue;
+            }
+
+            let ent = full.entities.entry(delta.number).or_insert_with(|| {
+                self.baselines
+                    .get(&delta.number)
+                    .cloned()
+                    .unwrap_or_else(|| DeltaEntity {
+                        number: delta.number,
+                        ..Default::default()
+                    })
+            });
+            ent.apply(delta);
+        }
+
+        self.frames.push_back(full);
+        if self.frames.len() > UPDATE_BACKUP {
+            self.frames.pop_front();
+        }
+    }
+}
+
+// the frame as a delta from `from`, or from the baselines if there's no `from`.
+pub fn encode_frame(
+    frame: &FullFrame,
+    from: Option<&FullFrame>,
+    baselines: &HashMap<i16, DeltaEntity>,
+) -> FrameMessage {
+    let empty = BTreeMap::new();
+    let old_entities = from.map_or(&empty, |f| &f.entities);
+    let mut entities = Vec::new();
+
+    for (number, ent) in &frame.entities {
+        let delta = match old_entities.get(number) {
+            Some(old) => {
+                let delta = ent.delta_from(old);
+                // it's carried over from the old frame as it is.
+                if delta
+                    == (DeltaEntity {
+                        number: *number,
+                        ..Default::default()
+                    })
+                {
+                    continue;
+                }
+                delta
+            }
+            // new ones start from their baseline, and have to be sent even if they match it.
+            None => ent.delta_from(&baselines.get(number).cloned().unwrap_or_default()),
+        };
+        entities.push(DeltaEntity {
+            number: *number,
+            ..delta
+        });
+    }
+    for number in old_entities.keys() {
+        if !frame.entities.contains_key(number) {
+            entities.push(DeltaEntity {
+                number: *number,
+                remove: true,
+                ..Default::default()
+            });
+        }
+    }
+    entities.sort_by_key(|ent| ent.number);
+
+    FrameMessage {
+        server_frame: frame.server_frame,
+        delta_frame: from.map_or(-1, |f| f.server_frame),
+        suppress_count: 0,
+        area_bits: frame.area_bits.clone(),
+        player_state: frame
+            .player_state
+            .delta_from(&from.map(|f| f.player_state.clone()).unwrap_or_default()),
+        entities,
+    }
+}
+
+// keeps just the frames in `range`. the cut demo gets a fresh header, and any frame that
+// deltas from something that was cut off is written out whole.
+pub fn cut_demo<R: Read, W: Write>(
+    reader: &mut DemoReader<R>,
+    writer: &mut DemoWriter<W>,
+    range: &CutRange,
+) -> Result<(), Q2ProtoError> {
+    let start = range.start.map_or(0, |p| p.frame());
+    let end = range.end.map(|p| p.frame());
+
+    let mut state = DemoState::new();
+    // frames from the start of the demo, counting this block's one.
+    let mut frame_count: u32 = 0;
+    let mut started = false;
+    // what a client playing the cut demo has, so it can take deltas from them.
+    let mut written: HashSet<i32> = HashSet::new();
+
+    while let Some(block) = reader.next_block()? {
+        let events = parse_message(&block)?;
+        let frame = events.iter().find_map(|evt| match evt {
+            ClientEvent::Frame(frame) => Some(frame),
+            _ => None,
+        });
+
+        if frame.is_some() {
+            frame_count += 1;
+            if end.is_some_and(|end| frame_count - 1 > end) {
+                break;
+            }
+        }
+
+        if !started {
+            if frame.is_none() || frame_count - 1 < start {
+                events.iter().for_each(|evt| state.update(evt));
+                continue;
+            }
+
+            started = true;
+            // a block with its own serverdata sets itself up.
+            let has_serverdata = events
+                .iter()
+                .any(|evt| evt.kind() == ClientEventKind::ServerData);
+            if let (Some(serverdata), false) = (state.serverdata(), has_serverdata) {
+                writer.write_header(serverdata, state.configstrings(), state.baselines())?;
+            }
+        }
+
+        let needs_rewrite = frame
+            .is_some_and(|frame| frame.delta_frame != -1 && !written.contains(&frame.delta_frame));
+        if !needs_rewrite {
+            events.iter().for_each(|evt| state.update(evt));
+            for evt in &events {
+                match evt {
+                    ClientEvent::ServerData(_) => written.clear(),
+                    ClientEvent::Frame(frame) => {
+                        written.insert(frame.server_frame);
+                    }
+                    _ => {}
+                }
+            }
+            writer.write_block(&block)?;
+            continue;
+        }
+
+        let mut rewritten = Vec::with_capacity(events.len());
+        for evt in events {
+            state.update(&evt);
+            match evt {
+                ClientEvent::ServerData(_) => {
+                    written.clear();
+                    rewritten.push(evt);
+                }
+                ClientEvent::Frame(frame) => {
+                    // the state has it whole now, if it could be rebuilt at all.
+                    if let Some(full) = state.frame(frame.server_frame) {
+                        let full = encode_frame(full, None, state.baselines());
+                        written.insert(full.server_frame);
+                        rewritten.push(ClientEvent::Frame(Box::new(full)));
+                    }
+                }
+                evt => rewritten.push(evt),
+            }
+        }
+        writer.write_events(&rewritten)?;
+    }
+
+    Ok(())
+}
+
+// drops every event `keep` says no to. blocks that end up empty are left out.
+pub fn filter_demo<R, W, F>(
+    reader: &mut DemoReader<R>,
+    writer: &mut DemoWriter<W>,
+    keep: F,
+) -> Result<(), Q2ProtoError>
+where
+    R: Read,
+    W: Write,
+    F: Fn(&ClientEvent) -> bool,
+{
+    while let Some(events) = reader.next_events()? {
+        let events: Vec<ClientEvent> = events.into_iter().filter(|evt| keep(evt)).collect();
+        if !events.is_empty() {
+            writer.write_events(&events)?;
+        }
+    }
+
+    Ok(())
+}
+
+// one demo after another. each one starts with its own serverdata, so to the client it's
+// just a level change.
+pub fn merge_demos<R: Read, W: Write>(
+    readers: &mut [DemoReader<R>],
+    writer: &mut DemoWriter<W>,
+) -> Result<(), Q2ProtoError> {
+    for reader in readers {
+        while let Some(block) = reader.next_block()? {
+            writer.write_block(&block)?;
+        }
+    }
+
+    Ok(())
+}
+
+// one player's view of an mvd as a regular demo, like they had recorded it themselves.
+// there's no map data, so everything is sent as if they could see it.
+pub fn extract_pov<R: Read, W: Write>(
+    reader: &mut MvdReader<R>,
+    writer: &mut DemoWriter<W>,
+    player: u8,
+) -> Result<(), Q2ProtoError> {
+    let mut baselines = HashMap::new();
+    let mut last_frame: Option<FullFrame> = None;
+    let mut server_frame = 0;
+
+    while let Some(events) = reader.next_events()? {
+        let state = reader.state();
+        let mut out = Vec::new();
+
+        // the serverdata comes with all the configstrings and a baseline frame, which all go
+        // into the header.
+        let new_level = events
+            .iter()
+            .any(|evt| matches!(evt, MvdEvent::ServerData(_)));
+        if new_level {
+            let serverdata = pov_serverdata(state, player)?;
+            baselines = state.entities().clone();
+            last_frame = None;
+            writer.write_header(&serverdata, state.configstrings(), &baselines)?;
+        }
+
+        for evt in events {
+            match evt {
+                MvdEvent::ServerData(_) => {}
+                MvdEvent::ConfigString(..) | MvdEvent::Frame(_) if new_level => {}
+                MvdEvent::ConfigString(index, value) => {
+                    out.push(ClientEvent::ConfigString(index, value))
+                }
+                MvdEvent::Frame(_) => {
+                    // they aren't in the game right now.
+                    let Some(view) = state.view(player) else {
+                        continue;
+                    };
+
+                    server_frame += 1;
+                    let frame = FullFrame {
+                        server_frame,
+                        area_bits: ALL_AREAS.to_vec(),
+                        player_state: view.player_state.clone(),
+                        entities: view
+                            .entities
+                            .iter()
+                            .map(|(number, ent)| (*number, ent.clone()))
+                            .collect(),
+                    };
+                    let msg = encode_frame(&frame, last_frame.as_ref(), &baselines);
+                    out.push(ClientEvent::Frame(Box::new(msg)));
+                    last_frame = Some(frame);
+                }
+                // stufftexts would run on whoever watches the demo.
+                MvdEvent::Unicast { client, events, .. } if client == player => out.extend(
+                    events
+                        .into_iter()
+                        .filter(|evt| evt.kind() != ClientEventKind::StuffText),
+                ),
+                MvdEvent::Unicast { .. } => {}
+                MvdEvent::Multicast { events, .. } => out.extend(events),
+                MvdEvent::Sound(sound) => out.push(ClientEvent::Sound(sound)),
+                MvdEvent::Print(level, text) => out.push(ClientEvent::Print(level, text)),
+                MvdEvent::StuffText(_) => {}
+            }
+        }
+
+        writer.write_events(&out)?;
+    }
+
+    Ok(())
+}
+
+fn pov_serverdata(state: &MvdState, player: u8) -> Result<ServerDataMessage, Q2ProtoError> {
+    let mvd = state.serverdata().ok_or_else(|| {
+        Q2ProtoError::MalformedResponse("mvd frame before serverdata".to_string())
+    })?;
+
+    Ok(ServerDataMessage {
+        protocol: ProtocolVersion::Vanilla as u32,
+        srv_count: mvd.server_count as u32,
+        attract_loop: 1,
+        gamedir: mvd.gamedir.clone(),
+        clnum: player as u16,
+        levelname: String::from_utf8_lossy(state.configstring(CS_NAME).unwrap_or_default())
+            .into_owned(),
+        protocol_info: ProtocolInfo::Vanilla,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mvd::tests::{event, frame, serverdata};
+    use crate::mvd::MVD_MAGIC;
+    use crate::objects::PrintLevel;
+    use crate::session::tests::serverdata as dm2_serverdata;
+    use std::io::Cursor;
+
+    // a header, then `frames` frames that each delta from the last, each with a print.
+    fn dm2(frames: i32) -> Vec<u8> {
+        let mut writer = DemoWriter::new(Vec::new());
+        let configstrings = HashMap::from([(CS_NAME, b"The Edge".to_vec())]);
+        let baselines = HashMap::from([(
+            1,
+            DeltaEntity {
+                number: 1,
+                model_index: Some(1),
+                ..Default::default()
+            },
+        )]);
+        writer
+            .write_header(&dm2_serverdata(), &configstrings, &baselines)
+            .unwrap();
+
+        for n in 1..=frames {
+            let mut player_state = PlayerState {
+                pm_type: (n == 1).then_some(0),
+                ..Default::default()
+            };
+            player_state.stats[1] = Some(100 - n as i16);
+
+            let frame = FrameMessage {
+                server_frame: n,
+                delta_frame: if n == 1 { -1 } else { n - 1 },
+                suppress_count: 0,
+                area_bits: vec![1],
+                player_state,
+                entities: vec![DeltaEntity {
+                    number: 1,
+                    origin0: Some(n as f32 * 8.0),
+                    ..Default::default()
+                }],
+            };
+            let print = format!("frame {}\n", n).into_bytes();
+            writer
+                .write_events(&[
+                    ClientEvent::Frame(Box::new(frame)),
+                    ClientEvent::Print(PrintLevel::HIGH, print),
+                ])
+                .unwrap();
+        }
+
+        writer.finish().unwrap()
+    }
+
+    fn events(demo: &[u8]) -> Vec<ClientEvent> {
+        DemoReader::new(demo).collect::<Result<_, _>>().unwrap()
+    }
+
+    fn frames(events: &[ClientEvent]) -> Vec<&FrameMessage> {
+        events
+            .iter()
+            .filter_map(|evt| match evt {
+                ClientEvent::Frame(frame) => Some(&**frame),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // everything a client playing it back would have once it's done.
+    fn played(events: &[ClientEvent]) -> DemoState {
+        let mut state = DemoState::new();
+        events.iter().for_each(|evt| state.update(evt));
+        state
+    }
+
+    fn cut(demo: &[u8], range: CutRange) -> Vec<u8> {
+        let mut writer = DemoWriter::new(Vec::new());
+        cut_demo(&mut DemoReader::new(demo), &mut writer, &range).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn cut_by_frame() {
+        let demo = dm2(10);
+        let cut = events(&cut(
+            &demo,
+            CutRange {
+                start: Some(DemoPosition::Frame(5)),
+                end: Some(DemoPosition::Frame(7)),
+            },
+        ));
+
+        // a new header, since the old one was cut off with the frames before.
+        let serverdata = ServerDataMessage {
+            attract_loop: 1,
+            ..dm2_serverdata()
+        };
+        assert_eq!(cut[0], ClientEvent::ServerData(serverdata));
+        assert!(cut.contains(&ClientEvent::StuffText(b"precache\n".to_vec())));
+
+        let kept = frames(&cut);
+        let numbers: Vec<i32> = kept.iter().map(|f| f.server_frame).collect();
+        assert_eq!(numbers, [6, 7, 8]);
+        // the first one's delta is gone, so it's written out whole.
+        assert_eq!(kept[0].delta_frame, -1);
+        assert_eq!(kept[1].delta_frame, 6);
+
+        let original = played(&events(&demo));
+        let cut = played(&cut);
+        for n in 6..=8 {
+            assert_eq!(cut.frame(n), original.frame(n), "frame {}", n);
+        }
+    }
+
+    #[test]
+    fn cut_by_time() {
+        let demo = dm2(10);
+        let by_frame = cut(
+            &demo,
+            CutRange {
+                start: Some(DemoPosition::Frame(5)),
+                end: None,
+            },
+        );
+        let by_time = cut(
+            &demo,
+            CutRange {
+                start: Some(DemoPosition::Time(FRAME_TIME * 5)),
+                end: None,
+            },
+        );
+        assert_eq!(by_time, by_frame);
+
+        let kept = events(&by_time);
+        let kept = frames(&kept);
+        assert_eq!(kept.len(), 5);
+        assert_eq!(kept[0].delta_frame, -1);
+    }
+
+    #[test]
+    fn filter_out_prints() {
+        let demo = dm2(3);
+        let mut writer = DemoWriter::new(Vec::new());
+        filter_demo(&mut DemoReader::new(&demo[..]), &mut writer, |evt| {
+            evt.kind() != ClientEventKind::Print
+        })
+        .unwrap();
+
+        let filtered = events(&writer.finish().unwrap());
+        let expected: Vec<ClientEvent> = events(&demo)
+            .into_iter()
+            .filter(|evt| evt.kind() != ClientEventKind::Print)
+            .collect();
+        assert_eq!(filtered, expected);
+        assert_eq!(frames(&filtered).len(), 3);
+    }
+
+    #[test]
+    fn merge_two() {
+        let first = dm2(2);
+        let second = dm2(3);
+        let mut writer = DemoWriter::new(Vec::new());
+        merge_demos(
+            &mut [DemoReader::new(&first[..]), DemoReader::new(&second[..])],
+            &mut writer,
+        )
+        .unwrap();
+
+        // one after the other, with the first one's end marker left out.
+        let mut expected = events(&first);
+        expected.extend(events(&second));
+        assert_eq!(events(&writer.finish().unwrap()), expected);
+    }
+
+    fn mvd(blocks: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = MVD_MAGIC.to_vec();
+        for block in blocks {
+            file.extend((block.len() as u16).to_le_bytes());
+            file.extend(block);
+        }
+        file.extend(0u16.to_le_bytes());
+        file
+    }
+
+    #[test]
+    fn pov_events_only_in_their_frame() {
+        let file = mvd(&[
+            serverdata(2),
+            frame(&[], &[event(5)]),
+            frame(&[], &[]),
+            frame(&[], &[]),
+        ]);
+
+        let mut reader = MvdReader::new(Cursor::new(file)).unwrap();
+        let mut writer = DemoWriter::new(Vec::new());
+        extract_pov(&mut reader, &mut writer, 2).unwrap();
+        let demo = writer.finish().unwrap();
+
+        let events: Vec<u8> = DemoReader::new(Cursor::new(demo))
+            .map(|evt| evt.unwrap())
+            .filter_map(|evt| match evt {
+                ClientEvent::Frame(frame) => Some(frame),
+                _ => None,
+            })
+            .map(|frame| {
+                frame
+                    .entities
+                    .iter()
+                    .find(|ent| ent.number == 5)
+                    .map_or(0, |ent| ent.event)
+            })
+            .collect();
+
+        assert_eq!(events, [2, 0, 0]);
+    }
+}
@@ -0,0 +1,135 @@
+use super::mvd::MvdOps;
+use super::{ProtocolVersion, ServerToClientOps};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Q2ProtoError {
+    // couldn't bind the local socket.
+    Bind(std::io::Error),
+    // anything else the socket complained about.
+    Io(std::io::Error),
+    // the server didn't answer in time.
+    Timeout,
+    // a connectionless reply we couldn't make sense of.
+    MalformedResponse(String),
+    // the server said no. the reason is our best guess from what it said.
+    Rejected(RejectReason, String),
+    // the message ended in the middle of this op.
+    Truncated(ServerToClientOps),
+    // same, in an mvd.
+    MvdTruncated(MvdOps),
+    UnknownOp(u8),
+    UnsupportedProtocol(ProtocolVersion),
+    // major and minor protocol of an mvd we can't read.
+    UnsupportedMvdVersion(i32, u16),
+    // there's no netchan to send that on.
+    NotConnected,
+    // the command doesn't fit in a message.
+    Overflow,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    ServerFull,
+    Banned,
+    // the password (or spectator_password) was missing or wrong.
+    BadPassword,
+    // the server speaks a different protocol or wants a different client.
+    VersionMismatch,
+    AnticheatRequired,
+    // bad challenge, game dll said no, etc.
+    Other,
+}
+
+impl RejectReason {
+    // servers don't send codes, just text, so go by what the stock servers, q2pro and r1q2 say.
+    pub fn from_message(msg: &str) -> RejectReason {
+        let msg = msg.to_ascii_lowercase();
+
+        if msg.contains("server is full") || msg.contains("server full") {
+            RejectReason::ServerFull
+        } else if msg.contains("banned") {
+            RejectReason::Banned
+        } else if msg.contains("password") {
+            RejectReason::BadPassword
+        } else if msg.contains("anticheat") || msg.contains("anti-cheat") {
+            RejectReason::AnticheatRequired
+        } else if msg.contains("version") || msg.contains("protocol") || msg.contains("requires") {
+            RejectReason::VersionMismatch
+        } else {
+            RejectReason::Other
+        }
+    }
+
+    // worth trying the same server again later. the rest won't change by waiting.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, RejectReason::ServerFull | RejectReason::Other)
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::ServerFull => write!(f, "server is full"),
+            RejectReason::Banned => write!(f, "banned"),
+            RejectReason::BadPassword => write!(f, "wrong or missing password"),
+            RejectReason::VersionMismatch => write!(f, "version mismatch"),
+            RejectReason::AnticheatRequired => write!(f, "anticheat required"),
+            RejectReason::Other => write!(f, "refused"),
+        }
+    }
+}
+
+impl Q2ProtoError {
+    // why the server turned us away, if that's what this is.
+    pub fn reject_reason(&self) -> Option<RejectReason> {
+        match self {
+            Q2ProtoError::Rejected(reason, _) => Some(*reason),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Q2ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Q2ProtoError::Bind(e) => write!(f, "couldn't bind socket: {}", e),
+            Q2ProtoError::Io(e) => write!(f, "socket error: {}", e),
+            Q2ProtoError::Timeout => write!(f, "timed out waiting for the server"),
+            Q2ProtoError::MalformedResponse(resp) => write!(f, "malformed response: {:?}", resp),
+            Q2ProtoError::Rejected(reason, msg) => {
+                write!(f, "server rejected us ({}): {}", reason, msg)
+            }
+            Q2ProtoError::Truncated(op) => write!(f, "message truncated at {:?}", op),
+            Q2ProtoError::MvdTruncated(op) => write!(f, "mvd message truncated at {:?}", op),
+            Q2ProtoError::UnknownOp(op) => write!(f, "unknown op {}", op),
+            Q2ProtoError::UnsupportedProtocol(proto) => {
+                write!(f, "protocol {:?} isn't supported", proto)
+            }
+            Q2ProtoError::UnsupportedMvdVersion(major, minor) => {
+                write!(f, "mvd protocol {}.{} isn't supported", major, minor)
+            }
+            Q2ProtoError::NotConnected => write!(f, "not connected"),
+            Q2ProtoError::Overflow => write!(f, "message overflow"),
+        }
+    }
+}
+
+impl std::error::Error for Q2ProtoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Q2ProtoError::Bind(e) | Q2ProtoError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Q2ProtoError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            // that's what a read timeout looks like.
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => Q2ProtoError::Timeout,
+            _ => Q2ProtoError::Io(e),
+        }
+    }
+}
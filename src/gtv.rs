@@ -0,0 +1,484 @@
+use super::error::{Q2ProtoError, RejectReason};
+use super::mvd::{MvdEvent, MvdState, MVD_MAGIC};
+use super::objects::{parse_string, write_string};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::read::ZlibDecoder;
+use std::collections::VecDeque;
+use std::io::{Cursor, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+pub const GTV_PROTOCOL_VERSION: u16 = 0xed04;
+// the server wants to hear from us at least this often, so that's how often we ping.
+pub const GTV_PING_INTERVAL: Duration = Duration::from_secs(60);
+// how late a ping can go out while we're waiting on a quiet stream.
+const GTV_READ_TIMEOUT: Duration = Duration::from_secs(GTV_PING_INTERVAL.as_secs() / 12);
+// deflate everything after the server's hello.
+pub const GTF_DEFLATE: u32 = 1 << 0;
+// the server takes GTC_STRINGCMD.
+pub const GTF_STRINGCMDS: u32 = 1 << 1;
+
+// gtv_clientop_t
+pub enum GtvClientOps {
+    Hello,
+    Ping,
+    StreamStart,
+    StreamStop,
+    StringCmd,
+}
+
+// gtv_serverop_t
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GtvServerOps {
+    Hello,
+    Pong,
+    StreamStart,
+    StreamStop,
+    StreamData,
+    Error,
+    BadRequest,
+    NoAccess,
+    Disconnect,
+    Reconnect,
+}
+
+impl TryFrom<u8> for GtvServerOps {
+    type Error = Q2ProtoError;
+
+    fn try_from(b: u8) -> Result<Self, Q2ProtoError> {
+        Ok(match b {
+            0 => GtvServerOps::Hello,
+            1 => GtvServerOps::Pong,
+            2 => GtvServerOps::StreamStart,
+            3 => GtvServerOps::StreamStop,
+            4 => GtvServerOps::StreamData,
+            5 => GtvServerOps::Error,
+            6 => GtvServerOps::BadRequest,
+            7 => GtvServerOps::NoAccess,
+            8 => GtvServerOps::Disconnect,
+            9 => GtvServerOps::Reconnect,
+            _ => return Err(Q2ProtoError::UnknownOp(b)),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GtvOptions {
+    // what the server shows for us in its gtv list.
+    pub name: String,
+    // sv_mvd_password, if the server has one.
+    pub password: String,
+    pub deflate: bool,
+    // how many frames the server may queue up for us.
+    pub max_buf: u16,
+}
+
+impl Default for GtvOptions {
+    fn default() -> Self {
+        GtvOptions {
+            name: "q2-proto".to_string(),
+            password: String::new(),
+            deflate: true,
+            max_buf: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GtvMessage {
+    Pong,
+    StreamStart,
+    StreamStop,
+    // mvd messages, same as a block in a .mvd2. empty while the server has nobody to watch.
+    StreamData(Vec<u8>),
+    // the server is restarting, the stream picks up again with new serverdata.
+    Reconnect,
+    Disconnect,
+}
+
+// everything after the hello may be deflated, and we still need to write to the stream.
+enum GtvInput<S: Read + Write> {
+    Plain(S),
+    Deflate(ZlibDecoder<S>),
+}
+
+impl<S: Read + Write> GtvInput<S> {
+    fn stream_mut(&mut self) -> &mut S {
+        match self {
+            GtvInput::Plain(s) => s,
+            GtvInput::Deflate(z) => z.get_mut(),
+        }
+    }
+}
+
+impl<S: Read + Write> Read for GtvInput<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            GtvInput::Plain(s) => s.read(buf),
+            GtvInput::Deflate(z) => z.read(buf),
+        }
+    }
+}
+
+// follows a live match over a q2pro server's gtv port (the game port, over tcp). the stream
+// is the same mvd a server would record, so this hands out the same events as MvdReader.
+pub struct GtvClient<S: Read + Write> {
+    input: GtvInput<S>,
+    state: MvdState,
+    server_flags: u32,
+    max_buf: u16,
+    pending: VecDeque<MvdEvent>,
+    last_sent: Instant,
+    done: bool,
+}
+
+impl GtvClient<TcpStream> {
+    pub fn connect(addr: &str, options: &GtvOptions) -> Result<Self, Q2ProtoError> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        // wake up often enough to ping on time when there's nothing to watch. a read that
+        // just started can keep us waiting this long past when the ping was due.
+        stream.set_read_timeout(Some(GTV_READ_TIMEOUT))?;
+
+        GtvClient::new(stream, options)
+    }
+}
+
+impl<S: Read + Write> GtvClient<S> {
+    // does the handshake. the stream doesn't start until start_stream. pings only go out
+    // between reads, so give your own stream a read timeout well under GTV_PING_INTERVAL.
+    pub fn new(mut stream: S, options: &GtvOptions) -> Result<Self, Q2ProtoError> {
+        stream.write_all(MVD_MAGIC)?;
+
+        let mut magic = [0u8; 4];
+        stream.read_exact(&mut magic)?;
+        if &magic != MVD_MAGIC {
+            return Err(Q2ProtoError::MalformedResponse(
+                String::from_utf8_lossy(&magic).into_owned(),
+            ));
+        }
+
+        let mut flags = GTF_STRINGCMDS;
+        if options.deflate {
+            flags |= GTF_DEFLATE;
+        }
+
+        let mut hello = Vec::new();
+        let _ = hello.write_u16::<LittleEndian>(GTV_PROTOCOL_VERSION);
+        let _ = hello.write_u32::<LittleEndian>(flags);
+        let _ = hello.write_u32::<LittleEndian>(0); // reserved
+        write_string(&mut hello, options.name.as_bytes());
+        write_string(&mut hello, options.password.as_bytes());
+        write_string(
+            &mut hello,
+            concat!("q2-proto ", env!("CARGO_PKG_VERSION")).as_bytes(),
+        );
+        write_message(&mut stream, GtvClientOps::Hello, &hello)?;
+
+        // nothing comes before the hello, and it's never deflated.
+        let (op, data) = read_message(&mut stream)?;
+        if op != GtvServerOps::Hello {
+            return Err(server_error(op, &data));
+        }
+        let server_flags = Cursor::new(&data)
+            .read_u32::<LittleEndian>()
+            .map_err(|_| Q2ProtoError::MalformedResponse("short gtv hello".to_string()))?;
+
+        let input = if server_flags & GTF_DEFLATE != 0 {
+            GtvInput::Deflate(ZlibDecoder::new(stream))
+        } else {
+            GtvInput::Plain(stream)
+        };
+
+        Ok(GtvClient {
+            input,
+            state: MvdState::new(),
+            server_flags,
+            max_buf: options.max_buf,
+            pending: VecDeque::new(),
+            last_sent: Instant::now(),
+            done: false,
+        })
+    }
+
+    // what the server agreed to, GTF_ bits.
+    pub fn server_flags(&self) -> u32 {
+        self.server_flags
+    }
+
+    // where the match is at, for views and the like.
+    pub fn state(&self) -> &MvdState {
+        &self.state
+    }
+
+    pub fn start_stream(&mut self) -> Result<(), Q2ProtoError> {
+        let mut data = Vec::new();
+        let _ = data.write_u16::<LittleEndian>(self.max_buf);
+        self.send(GtvClientOps::StreamStart, &data)
+    }
+
+    pub fn stop_stream(&mut self) -> Result<(), Q2ProtoError> {
+        self.send(GtvClientOps::StreamStop, &[])
+    }
+
+    pub fn ping(&mut self) -> Result<(), Q2ProtoError> {
+        self.send(GtvClientOps::Ping, &[])
+    }
+
+    // a console command for the server, if it takes them (GTF_STRINGCMDS).
+    pub fn string_cmd(&mut self, cmd: &str) -> Result<(), Q2ProtoError> {
+        let mut data = Vec::new();
+        write_string(&mut data, cmd.as_bytes());
+        self.send(GtvClientOps::StringCmd, &data)
+    }
+
+    // the next message from the server. pings whenever it's been quiet for too long.
+    pub fn next_message(&mut self) -> Result<GtvMessage, Q2ProtoError> {
+        loop {
+            if self.last_sent.elapsed() >= GTV_PING_INTERVAL {
+                self.ping()?;
+            }
+
+            // only the first byte may time out, so we never lose our place in the stream.
+            let mut first = [0u8; 1];
+            match self.input.read(&mut first) {
+                Ok(0) => return Ok(GtvMessage::Disconnect),
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue
+                }
+                Err(e) => return Err(Q2ProtoError::Io(e)),
+            }
+
+            let (op, data) = read_message_rest(&mut self.input, first[0])?;
+            return match op {
+                GtvServerOps::Pong => Ok(GtvMessage::Pong),
+                GtvServerOps::StreamStart => Ok(GtvMessage::StreamStart),
+                GtvServerOps::StreamStop => Ok(GtvMessage::StreamStop),
+                GtvServerOps::StreamData => Ok(GtvMessage::StreamData(data)),
+                GtvServerOps::Reconnect => Ok(GtvMessage::Reconnect),
+                GtvServerOps::Disconnect => Ok(GtvMessage::Disconnect),
+                _ => Err(server_error(op, &data)),
+            };
+        }
+    }
+
+    // the events in the next bit of stream data. None once the server hung up.
+    pub fn next_events(&mut self) -> Result<Option<Vec<MvdEvent>>, Q2ProtoError> {
+        if self.done {
+            return Ok(None);
+        }
+
+        loop {
+            match self.next_message()? {
+                GtvMessage::StreamData(data) if !data.is_empty() => {
+                    return Ok(Some(self.state.parse_message(&data)?))
+                }
+                GtvMessage::Disconnect => {
+                    self.done = true;
+                    return Ok(None);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn send(&mut self, op: GtvClientOps, data: &[u8]) -> Result<(), Q2ProtoError> {
+        write_message(self.input.stream_mut(), op, data)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
+
+// stops at the first error.
+impl<S: Read + Write> Iterator for GtvClient<S> {
+    type Item = Result<MvdEvent, Q2ProtoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(evt) = self.pending.pop_front() {
+                return Some(Ok(evt));
+            }
+
+            match self.next_events() {
+                Ok(Some(events)) => self.pending.extend(events),
+                Ok(None) => return None,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// 16 bit length (counting the op), the op, then the data. same both ways.
+fn write_message<W: Write>(
+    stream: &mut W,
+    op: GtvClientOps,
+    data: &[u8],
+) -> Result<(), Q2ProtoError> {
+    let len = u16::try_from(data.len() + 1).map_err(|_| Q2ProtoError::Overflow)?;
+
+    let mut msg = Vec::with_capacity(data.len() + 3);
+    let _ = msg.write_u16::<LittleEndian>(len);
+    msg.push(op as u8);
+    msg.extend_from_slice(data);
+
+    stream.write_all(&msg)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn read_message<R: Read>(stream: &mut R) -> Result<(GtvServerOps, Vec<u8>), Q2ProtoError> {
+    let first = stream.read_u8()?;
+    read_message_rest(stream, first)
+}
+
+fn read_message_rest<R: Read>(
+    stream: &mut R,
+    first: u8,
+) -> Result<(GtvServerOps, Vec<u8>), Q2ProtoError> {
+    let len = u16::from_le_bytes([first, stream.read_u8()?]) as usize;
+    if len == 0 {
+        return Err(Q2ProtoError::MalformedResponse(
+            "empty gtv message".to_string(),
+        ));
+    }
+
+    let mut msg = vec![0u8; len];
+    stream.read_exact(&mut msg)?;
+
+    Ok((GtvServerOps::try_from(msg[0])?, msg.split_off(1)))
+}
+
+// anything the server sends instead of what we asked for.
+fn server_error(op: GtvServerOps, data: &[u8]) -> Q2ProtoError {
+    match op {
+        GtvServerOps::Error => {
+            let msg = String::from_utf8_lossy(&parse_string(&mut Cursor::new(data))).into_owned();
+            Q2ProtoError::Rejected(RejectReason::from_message(&msg), msg)
+        }
+        GtvServerOps::NoAccess => {
+            Q2ProtoError::Rejected(RejectReason::BadPassword, "no gtv access".to_string())
+        }
+        GtvServerOps::Disconnect => Q2ProtoError::NotConnected,
+        _ => Q2ProtoError::MalformedResponse(format!("unexpected gtv op {:?}", op)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::PrintLevel;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn server_message(op: GtvServerOps, data: &[u8]) -> Vec<u8> {
+        let mut msg = ((data.len() + 1) as u16).to_le_bytes().to_vec();
+        msg.push(op as u8);
+        msg.extend_from_slice(data);
+        msg
+    }
+
+    fn client_message(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let len = stream.read_u16::<LittleEndian>().unwrap() as usize;
+        let mut msg = vec![0u8; len];
+        stream.read_exact(&mut msg).unwrap();
+        (msg[0], msg.split_off(1))
+    }
+
+    // a q2pro gtv port that says hello, starts the stream and sends one print.
+    fn stand_in(deflate: bool) -> (String, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut magic = [0u8; 4];
+            stream.read_exact(&mut magic).unwrap();
+            assert_eq!(&magic, MVD_MAGIC);
+            stream.write_all(MVD_MAGIC).unwrap();
+
+            let (op, hello) = client_message(&mut stream);
+            assert_eq!(op, 0);
+            let mut cur = Cursor::new(&hello);
+            assert_eq!(
+                cur.read_u16::<LittleEndian>().unwrap(),
+                GTV_PROTOCOL_VERSION
+            );
+            let flags = cur.read_u32::<LittleEndian>().unwrap();
+            assert_eq!(flags & GTF_DEFLATE != 0, deflate);
+
+            let flags = if deflate { GTF_DEFLATE } else { 0 };
+            let hello = server_message(GtvServerOps::Hello, &flags.to_le_bytes());
+            stream.write_all(&hello).unwrap();
+
+            let (op, max_buf) = client_message(&mut stream);
+            assert_eq!(op, 2);
+            assert_eq!(max_buf, 10u16.to_le_bytes());
+
+            // mvd_print, PRINT_HIGH
+            let mut data = vec![17, 2];
+            write_string(&mut data, b"hello");
+
+            let mut out = server_message(GtvServerOps::StreamStart, &[]);
+            out.extend(server_message(GtvServerOps::StreamData, &data));
+            if deflate {
+                let mut z = ZlibEncoder::new(stream, Compression::default());
+                z.write_all(&out).unwrap();
+                z.finish().unwrap();
+            } else {
+                stream.write_all(&out).unwrap();
+            }
+        });
+
+        (addr, server)
+    }
+
+    fn watch(deflate: bool) {
+        let (addr, server) = stand_in(deflate);
+        let options = GtvOptions {
+            deflate,
+            ..Default::default()
+        };
+
+        let mut client = GtvClient::connect(&addr, &options).unwrap();
+        assert_eq!(client.server_flags() & GTF_DEFLATE != 0, deflate);
+
+        client.start_stream().unwrap();
+        assert_eq!(client.next_message().unwrap(), GtvMessage::StreamStart);
+
+        let events = client.next_events().unwrap().unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [MvdEvent::Print(PrintLevel::HIGH, text)] if text == b"hello"
+        ));
+
+        // the server hung up.
+        assert!(client.next_events().unwrap().is_none());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn stream_plain() {
+        watch(false);
+    }
+
+    #[test]
+    fn stream_deflated() {
+        watch(true);
+    }
+
+    #[test]
+    fn unknown_op() {
+        let msg = [2u8, 0, 42, 0];
+        assert!(matches!(
+            read_message(&mut Cursor::new(msg)),
+            Err(Q2ProtoError::UnknownOp(42))
+        ));
+    }
+}
@@ -1,22 +1,53 @@
+#[cfg(feature = "async")]
+pub mod async_client;
+pub mod chat;
+pub mod connection;
+pub mod demo;
+pub mod demo_edit;
+pub mod error;
+pub mod gtv;
+pub mod master;
+pub mod master_server;
+pub mod message;
 pub mod msg_buf;
+pub mod mvd;
 pub mod netchan;
+pub mod obituary;
 pub mod objects;
+pub mod rcon;
+pub mod session;
+pub mod status;
+pub mod subscription;
+pub mod text;
 pub mod user_info;
 
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use chat::{say_command, ChatMessage};
+use connection::{ClientTimeouts, ConnectionState, NegotiateOptions};
+use demo::DemoWriter;
+use error::Q2ProtoError;
 use msg_buf::MsgBuf;
-use netchan::{NetChan, NetChanVanilla};
+use netchan::RateLimit;
 use objects::{
-    parse_baseline, parse_configstring, parse_print, parse_serverdata, parse_string, DeltaEntity,
-    PrintLevel, ServerDataMessage,
+    DeltaEntity, FrameMessage, MuzzleFlash, PrintLevel, ServerDataMessage, SoundMessage,
 };
-use std::collections::HashMap;
-use std::io::{Cursor, ErrorKind, Write};
-use std::net::UdpSocket;
+use rcon::{
+    parse_dumpuser, parse_rcon_reply, parse_serverinfo, parse_status, rcon_packet, RconStatus,
+    RCON_MORE_WAIT,
+};
+use session::{connectionless_payload, oob_packet, parse_challenge, ClientSession};
+use status::{ServerInfo, ServerStatus};
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::mpsc::Sender;
 use std::time::{Duration, Instant};
+use subscription::Subscription;
 use user_info::UserInfo;
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum ProtocolVersion {
     Vanilla = 34,
     R1Q2 = 35,
@@ -42,6 +73,9 @@ pub enum ClientToServerOps {
 const MAX_WRITEABLE_SIZE: usize = 4096;
 const MAX_NET_STRING: usize = 2048;
 const OOB_PREFIX: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(1);
+// zero isn't a valid socket timeout.
+const MIN_READ_WAIT: Duration = Duration::from_millis(1);
 
 #[allow(dead_code)]
 pub struct Challenge {
@@ -120,6 +154,7 @@ impl From<u8> for ServerToClientOps {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum ClientEvent {
     Disconnect,
     Reconnect,
@@ -129,382 +164,676 @@ pub enum ClientEvent {
     ServerData(ServerDataMessage),
     ConfigString(u16, Vec<u8>),
     DeltaEntity(DeltaEntity),
+    Frame(Box<FrameMessage>),
+    MuzzleFlash(MuzzleFlash),
+    // entity, monster flash number
+    MuzzleFlash2(i16, u8),
+    // kind, and the rest of it as it came
+    TempEntity(u8, Vec<u8>),
+    Sound(SoundMessage),
+    Layout(Vec<u8>),
+    Inventory(Vec<i16>),
+    // a chat print someone said, comes right after the Print it was in.
+    Chat(ChatMessage),
+    // nothing came from the server for too long, or it never answered the handshake.
+    Timeout,
+}
+
+// What sort of event it is, without the payload. Used to filter subscriptions.
+#[derive(Eq, Hash, PartialEq, Clone, Copy, Debug)]
+pub enum ClientEventKind {
+    Disconnect,
+    Reconnect,
+    Print,
+    StuffText,
+    CenterPrint,
+    ServerData,
+    ConfigString,
+    DeltaEntity,
+    Frame,
+    MuzzleFlash,
+    MuzzleFlash2,
+    TempEntity,
+    Sound,
+    Layout,
+    Inventory,
+    Chat,
+    Timeout,
+}
+
+impl ClientEvent {
+    pub fn kind(&self) -> ClientEventKind {
+        match self {
+            ClientEvent::Disconnect => ClientEventKind::Disconnect,
+            ClientEvent::Reconnect => ClientEventKind::Reconnect,
+            ClientEvent::Print(..) => ClientEventKind::Print,
+            ClientEvent::StuffText(_) => ClientEventKind::StuffText,
+            ClientEvent::CenterPrint(_) => ClientEventKind::CenterPrint,
+            ClientEvent::ServerData(_) => ClientEventKind::ServerData,
+            ClientEvent::ConfigString(..) => ClientEventKind::ConfigString,
+            ClientEvent::DeltaEntity(_) => ClientEventKind::DeltaEntity,
+            ClientEvent::Frame(_) => ClientEventKind::Frame,
+            ClientEvent::MuzzleFlash(_) => ClientEventKind::MuzzleFlash,
+            ClientEvent::MuzzleFlash2(..) => ClientEventKind::MuzzleFlash2,
+            ClientEvent::TempEntity(..) => ClientEventKind::TempEntity,
+            ClientEvent::Sound(_) => ClientEventKind::Sound,
+            ClientEvent::Layout(_) => ClientEventKind::Layout,
+            ClientEvent::Inventory(_) => ClientEventKind::Inventory,
+            ClientEvent::Chat(_) => ClientEventKind::Chat,
+            ClientEvent::Timeout => ClientEventKind::Timeout,
+        }
+    }
 }
 
-type ClientEventListener = fn(&ClientEvent);
+pub struct ClientEvents<'a> {
+    client: &'a mut Q2ProtoClient,
+    pending: VecDeque<ClientEvent>,
+    done: bool,
+}
+
+// stops at the first error, or without one once the connection is gone.
+impl Iterator for ClientEvents<'_> {
+    type Item = Result<ClientEvent, Q2ProtoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(evt) = self.pending.pop_front() {
+                return Some(Ok(evt));
+            }
+            if self.done {
+                return None;
+            }
+
+            match self.client.pump() {
+                Ok(events) => self.pending.extend(events),
+                Err(Q2ProtoError::NotConnected) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
 
 pub struct Q2ProtoClient {
     socket: UdpSocket,
     server_address: String,
-    bind_port: u16,
-    connected: bool,
-    chan: Box<NetChanVanilla>,
-    events: HashMap<ServerToClientOps, Vec<ClientEventListener>>,
-    version: String,
-    last_precache_value: u32,
-    last_msg_sent_time: Instant,
+    read_timeout: Cell<Duration>,
+    session: ClientSession,
+    recording: Option<DemoWriter<Box<dyn Write + Send>>>,
 }
 
 impl Q2ProtoClient {
-    pub fn new(server: &str, bind_addr: &str, bind_port: u16, version: &str) -> Option<Q2ProtoClient> {
-        let socket_opt = UdpSocket::bind(format!("{}:{}", bind_addr, bind_port));
-        let socket= match socket_opt {
-            Ok(s) => s,
-            _ => {
-                return None;
-            }
-        };
-
-        Some(Q2ProtoClient {
+    pub fn new(
+        server: &str,
+        bind_addr: &str,
+        bind_port: u16,
+        version: &str,
+    ) -> Result<Q2ProtoClient, Q2ProtoError> {
+        let socket =
+            UdpSocket::bind(format!("{}:{}", bind_addr, bind_port)).map_err(Q2ProtoError::Bind)?;
+
+        Ok(Q2ProtoClient {
             socket,
             server_address: server.to_owned(),
-            bind_port: bind_port,
-            connected: false,
-            chan: Box::new(NetChanVanilla::new(true, bind_port)),
-            events: HashMap::new(),
-            version: version.to_string(),
-            last_precache_value: 0,
-            last_msg_sent_time: Instant::now(),
+            // without this a quiet server would block us forever and we'd never notice it's gone.
+            read_timeout: Cell::new(DEFAULT_READ_TIMEOUT),
+            session: ClientSession::new(bind_port, version),
+            recording: None,
         })
     }
 
     pub fn is_connected(&self) -> bool {
-        self.connected
+        self.session.state().has_netchan()
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.session.state()
+    }
+
+    pub fn timeouts(&self) -> ClientTimeouts {
+        self.session.timeouts()
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: ClientTimeouts) {
+        self.session.set_timeouts(timeouts);
+    }
+
+    // overrides the cadence `connect` picked up from the userinfo `cl_maxfps`.
+    pub fn set_rate_limit(&mut self, rate: RateLimit) {
+        self.session.set_rate_limit(rate);
+    }
+
+    pub fn rate_limit(&self) -> RateLimit {
+        self.session.rate_limit()
     }
 
+    // how long `pump` and the OOB queries block waiting for a packet.
     pub fn set_read_timeout(&self, timeout: Duration) -> std::io::Result<()> {
-        self.socket.set_read_timeout(Some(timeout))
+        self.socket.set_read_timeout(Some(timeout))?;
+        self.read_timeout.set(timeout);
+
+        Ok(())
     }
 
     fn oob_print(&self, msg: &[u8]) -> std::io::Result<usize> {
-        let mut send = Vec::with_capacity(4 + msg.len());
-        send.extend_from_slice(OOB_PREFIX.as_slice());
-        send.extend_from_slice(msg);
-        self.socket.send_to(&send, &self.server_address)
+        self.socket.send_to(&oob_packet(msg), &self.server_address)
     }
 
-    fn recv_connectionless(&self) -> Option<String> {
+    fn recv_connectionless_bytes(&self) -> Result<Vec<u8>, Q2ProtoError> {
+        self.recv_connectionless_within(self.read_timeout.get())
+    }
+
+    // the next connectionless packet from our server. once we're connected the netchan
+    // packets come in on the same socket, and anyone can send us junk, so all that gets
+    // skipped until the answer shows up or the time is up.
+    fn recv_connectionless_within(&self, timeout: Duration) -> Result<Vec<u8>, Q2ProtoError> {
         let mut buf = [0u8; 1500];
-        let recv_bytes = if self.connected {
-            self.socket.recv(&mut buf).ok()?
-        } else {
-            let (bytes, _addr) = self.socket.recv_from(&mut buf).ok()?;
-            if _addr != self.server_address.parse().unwrap() {
-                return None; // not our server...
-            }
+        let server: Vec<SocketAddr> = self.server_address.to_socket_addrs()?.collect();
+        let deadline = Instant::now() + timeout;
 
-            bytes
-        };
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            if wait.is_zero() {
+                return Err(Q2ProtoError::Timeout);
+            }
+            self.socket.set_read_timeout(Some(wait))?;
 
-        if buf[..4] != OOB_PREFIX {
-            return None; // not connectionless
+            let (recv_bytes, addr) = self.socket.recv_from(&mut buf)?;
+            if !server.contains(&addr) {
+                continue; // not our server...
+            }
+            if let Some(payload) = connectionless_payload(&buf[..recv_bytes]) {
+                return Ok(payload.to_vec());
+            }
         }
+    }
+
+    fn recv_connectionless(&self) -> Result<String, Q2ProtoError> {
+        String::from_utf8(self.recv_connectionless_bytes()?).map_err(|e| {
+            Q2ProtoError::MalformedResponse(String::from_utf8_lossy(e.as_bytes()).into_owned())
+        })
+    }
 
-        String::from_utf8(buf[4..recv_bytes].to_vec()).ok()
+    pub fn status(&self) -> Result<ServerStatus, Q2ProtoError> {
+        self.oob_print(b"status")?;
+        ServerStatus::parse(&self.recv_connectionless_bytes()?)
     }
 
-    pub fn status(&self) -> Option<String> {
-        self.oob_print(b"status").ok()?;
-        self.recv_connectionless()
+    // lighter than status, just hostname, map and player count.
+    pub fn info(&self, proto: ProtocolVersion) -> Result<ServerInfo, Q2ProtoError> {
+        self.oob_print(format!("info {}", proto as i32).as_bytes())?;
+        ServerInfo::parse(&self.recv_connectionless_bytes()?)
     }
 
-    pub fn challenge(&self) -> Option<Challenge> {
-        self.oob_print(b"getchallenge").ok()?;
+    pub fn challenge(&self) -> Result<Challenge, Q2ProtoError> {
+        self.oob_print(b"getchallenge")?;
 
         // we're good. skip the prefix and return the challenge
         let str = self.recv_connectionless()?;
-        let mut split_pat = str.split(' ');
-        if split_pat.next() != Some("challenge") {
-            return None;
-        };
+        parse_challenge(&str)
+    }
 
-        let ch_value: &str = split_pat.next()?;
-        let protos: &str = split_pat.next()?;
+    // runs a console command on the server and returns everything it printed.
+    pub fn rcon(&self, password: &str, cmd: &str) -> Result<String, Q2ProtoError> {
+        self.socket
+            .send_to(&rcon_packet(password, cmd), &self.server_address)?;
 
-        if !protos.starts_with("p=") {
-            return None;
+        let mut reply = parse_rcon_reply(&self.recv_connectionless_bytes()?)?;
+        loop {
+            match self.recv_connectionless_within(RCON_MORE_WAIT) {
+                Ok(payload) => reply.push_str(&parse_rcon_reply(&payload)?),
+                Err(Q2ProtoError::Timeout) => break,
+                Err(e) => return Err(e),
+            }
         }
 
-        Some(Challenge {
-            ch_value: String::from(ch_value),
-            protocols: String::from(&protos[2..]),
-        })
+        Ok(reply)
     }
 
-    pub fn send_command(&mut self, cmd: &str) -> Option<()> {
-        if !self.connected {
-            return None;
-        }
+    // who's on, as the server console sees it.
+    pub fn rcon_status(&self, password: &str) -> Result<RconStatus, Q2ProtoError> {
+        Ok(parse_status(&self.rcon(password, "status")?))
+    }
 
-        self.chan
-            .message
-            .cur
-            .write_u8(ClientToServerOps::StringCmd as u8)
-            .ok()?;
-        self.chan.message.write_string(cmd)?;
+    pub fn kick(&self, password: &str, slot: u32) -> Result<String, Q2ProtoError> {
+        self.rcon(password, &format!("kick {}", slot))
+    }
 
-        Some(())
+    pub fn changemap(&self, password: &str, map: &str) -> Result<String, Q2ProtoError> {
+        self.rcon(password, &format!("map {}", map))
     }
 
+    pub fn serverinfo(&self, password: &str) -> Result<HashMap<String, String>, Q2ProtoError> {
+        Ok(parse_serverinfo(&self.rcon(password, "serverinfo")?))
+    }
+
+    // the userinfo of whoever is in that slot.
+    pub fn dumpuser(&self, password: &str, slot: u32) -> Result<UserInfo, Q2ProtoError> {
+        parse_dumpuser(&self.rcon(password, &format!("dumpuser {}", slot))?)
+    }
+
+    pub fn send_command(&mut self, cmd: &str) -> Result<(), Q2ProtoError> {
+        self.session.send_command(cmd)
+    }
+
+    // quoted and cut to length, so it comes out the way it was written.
+    pub fn say(&mut self, text: &str) -> Result<(), Q2ProtoError> {
+        self.session
+            .send_command(&say_command(&self.session.name(), text, false))
+    }
+
+    // same, only our team sees it.
+    pub fn say_team(&mut self, text: &str) -> Result<(), Q2ProtoError> {
+        self.session
+            .send_command(&say_command(&self.session.name(), text, true))
+    }
+
+    // connect with a challenge we already have. blocks until the server lets us in or we give up.
     pub fn connect(
         &mut self,
         challenge: Challenge,
         proto: ProtocolVersion,
         userinfo: UserInfo,
-    ) -> Option<()> {
-        // woops it takes more work than this to get r1q2 and q2pro support!
-        self.last_msg_sent_time = Instant::now();
-        assert_eq!(proto, ProtocolVersion::Vanilla);
+    ) -> Result<(), Q2ProtoError> {
+        self.socket.connect(&self.server_address)?;
+        self.session
+            .start_connect(challenge, proto, userinfo, Instant::now())?;
+        self.flush()?;
+
+        self.wait_for_handshake()
+    }
 
-        // send the connect message
-        let msg = format!(
-            "connect {} {} {} \"{}\"\n",
-            proto as u8,
-            self.bind_port,
-            challenge.ch_value,
-            userinfo.as_string()
-        );
+    // do the whole process to get into a server.
+    pub fn negotiate(
+        &mut self,
+        proto: ProtocolVersion,
+        userinfo: UserInfo,
+    ) -> Result<(), Q2ProtoError> {
+        self.negotiate_with(proto, userinfo, &NegotiateOptions::default())
+    }
 
-        self.oob_print(msg.as_ref()).ok()?;
+    // same as negotiate, for locked servers. a wrong password comes back as
+    // Rejected(RejectReason::BadPassword, ..).
+    pub fn negotiate_with(
+        &mut self,
+        proto: ProtocolVersion,
+        mut userinfo: UserInfo,
+        options: &NegotiateOptions,
+    ) -> Result<(), Q2ProtoError> {
+        options.apply(&mut userinfo);
 
-        self.socket.connect(&self.server_address).ok()?;
-        self.connected = true; // we did it! we're considered to be 'connected'.
+        self.socket.connect(&self.server_address)?;
+        self.session
+            .start_challenge(proto, userinfo, Instant::now())?;
+        self.flush()?;
 
-        self.parse_client_connect();
+        self.wait_for_handshake()
+    }
 
-        self.send_command("new");
+    fn wait_for_handshake(&mut self) -> Result<(), Q2ProtoError> {
+        // whatever comes in meanwhile stays queued for the next pump.
+        while self.session.state().is_handshaking() {
+            self.pump_packets()?;
+        }
 
-        Some(())
+        match self.session.take_handshake_error() {
+            Some(e) => Err(e),
+            None if self.session.state().has_netchan() => Ok(()),
+            None => Err(Q2ProtoError::NotConnected),
+        }
     }
 
-    fn parse_command<T: AsRef<[u8]>>(
-        &mut self,
-        cursor: &mut Cursor<T>,
-    ) -> Result<Vec<ClientEvent>, std::io::Error> {
-        let mut evts = vec![];
+    pub fn configstring(&self, index: u16) -> Option<&[u8]> {
+        self.session.configstring(index)
+    }
 
-        loop {
-            let cmd_val = cursor.read_u8();
-            if cmd_val.is_err() {
-                break;
-            }
+    pub fn configstrings(&self) -> &HashMap<u16, Vec<u8>> {
+        self.session.configstrings()
+    }
 
-            let cmd = ServerToClientOps::from(cmd_val.unwrap());
+    pub fn baseline(&self, number: i16) -> Option<&DeltaEntity> {
+        self.session.baseline(number)
+    }
 
-            let op: Option<ClientEvent> = match cmd {
-                ServerToClientOps::Bad => {
-                    return Err(std::io::Error::from(ErrorKind::InvalidInput));
-                }
-                ServerToClientOps::MuzzleFlash => None,
-                ServerToClientOps::MuzzleFlash2 => None,
-                ServerToClientOps::TempEntity => None,
-                ServerToClientOps::Layout => None,
-                ServerToClientOps::Inventory => None,
-                ServerToClientOps::Nop => None,
-                ServerToClientOps::Disconnect => {
-                    println!("DISCONNECT BYTE RECV");
-                    self.send_command("disconnect");
-                    Some(ClientEvent::Disconnect)
-                }
-                ServerToClientOps::Reconnect => {
-                    println!("RECONNECT BYTE RECV");
-                    self.send_command("disconnect");
-                    Some(ClientEvent::Reconnect)
-                }
-                ServerToClientOps::Sound => None,
-                ServerToClientOps::Print => parse_print(cursor),
-                ServerToClientOps::StuffText => {
-                    // If we receive a \177c (7f6c -- a short) we need to reply with a command
-                    // containing whatever value it requested of us.
-                    let str = parse_string(cursor);
-                    if self.check_stuffcmd(&str) {
-                        Some(ClientEvent::StuffText(str))
-                    } else {
-                        None
-                    }
-                }
-                ServerToClientOps::ServerData => parse_serverdata(cursor),
-                ServerToClientOps::ConfigString => parse_configstring(cursor),
-                ServerToClientOps::SpawnBaseline => parse_baseline(cursor),
-                ServerToClientOps::CenterPrint => {
-                    Some(ClientEvent::CenterPrint(parse_string(cursor)))
-                }
-                ServerToClientOps::Download => None,
-                ServerToClientOps::PlayerInfo => {
-                    None // this should be included in Frame
-                }
-                ServerToClientOps::PacketEntities => None,
-                ServerToClientOps::DeltaPacketEntities => None,
-                ServerToClientOps::Frame => None,
-                ServerToClientOps::ZPacket => None,
-                ServerToClientOps::ZDownload => None,
-                ServerToClientOps::Gamestate => None,
-                ServerToClientOps::Setting => None,
-                ServerToClientOps::Invalid => None,
-            };
-
-            if let Some(unwrapped_op) = op {
-                let vec_listeners = self.events.get(&cmd);
-
-                if let Some(listeners) = vec_listeners {
-                    for item in listeners {
-                        item(&unwrapped_op);
-                    }
-                }
+    pub fn baselines(&self) -> &HashMap<i16, DeltaEntity> {
+        self.session.baselines()
+    }
 
-                evts.push(unwrapped_op)
-            } else {
-                // println!("UNABLE TO PARSE: ServerToClientOps::{:?}", cmd);
-                break;
-            }
+    // write everything the server sends from here on to a .dm2. we have to be in the game
+    // already, the header is made up from what we know about the level so far.
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Q2ProtoError> {
+        if self.session.state() != ConnectionState::Active {
+            return Err(Q2ProtoError::NotConnected);
         }
 
-        Ok(evts)
+        self.start_recording_to(BufWriter::new(File::create(path)?))
     }
 
-    fn parse_client_connect(&mut self) -> Option<()> {
-        let data = self.recv_connectionless()?;
-        let mut response = data.split(' ');
+    // same, into anything that takes bytes.
+    pub fn start_recording_to<W: Write + Send + 'static>(
+        &mut self,
+        writer: W,
+    ) -> Result<(), Q2ProtoError> {
+        let serverdata = match self.session.serverdata() {
+            Some(serverdata) if self.session.state() == ConnectionState::Active => serverdata,
+            _ => return Err(Q2ProtoError::NotConnected),
+        };
 
-        if response.next() != Some("client_connect") {
-            return None;
-        }
+        let mut demo = DemoWriter::new(Box::new(writer) as Box<dyn Write + Send>);
+        demo.write_header(
+            serverdata,
+            self.session.configstrings(),
+            self.session.baselines(),
+        )?;
 
-        for re in response {
-            if re.starts_with("ac=") {
-                // anticheat
-                self.connected = false;
-                return None;
-            } 
-            // else if re.starts_with("map=") { // map
-            // } else if re.starts_with("nc=") { // netchan
-            // }
-        }
+        self.session.set_capture_messages(true);
+        self.recording = Some(demo);
 
-        Some(())
+        Ok(())
     }
 
-    // do the whole process to get into a server.
-    pub fn negotiate(&mut self, proto: ProtocolVersion, userinfo: UserInfo) -> Option<()> {
-        let ch = self.challenge()?;
-        self.connect(ch, proto, userinfo);
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
 
-        Some(())
+    // finishes the demo off. does nothing if we weren't recording.
+    pub fn stop_recording(&mut self) -> Result<(), Q2ProtoError> {
+        self.session.set_capture_messages(false);
+        match self.recording.take() {
+            Some(demo) => demo.finish().map(|_| ()),
+            None => Ok(()),
+        }
     }
 
-    pub fn subscribe(&mut self, evt: ServerToClientOps, callback: ClientEventListener) {
-        self.events.entry(evt.clone()).or_default();
-        self.events.get_mut(&evt).unwrap().push(callback);
+    // tell the server we're leaving and forget about the connection.
+    pub fn disconnect(&mut self) {
+        self.session.disconnect(Instant::now());
+        let _ = self.flush();
     }
 
-    pub fn pump(&mut self) -> Result<(), std::io::Error> {
-        if !self.connected {
-            return Err(std::io::Error::from(ErrorKind::NotConnected));
-        }
+    // the listener stays registered until the returned handle is dropped.
+    pub fn subscribe<F>(&mut self, kind: ClientEventKind, callback: F) -> Subscription
+    where
+        F: FnMut(&ClientEvent) + Send + 'static,
+    {
+        self.session.subscribe(Some(kind), Box::new(callback))
+    }
 
-        let mut buf = [0u8; MAX_WRITEABLE_SIZE];
+    pub fn subscribe_all<F>(&mut self, callback: F) -> Subscription
+    where
+        F: FnMut(&ClientEvent) + Send + 'static,
+    {
+        self.session.subscribe(None, Box::new(callback))
+    }
 
-        while self.socket.peek(&mut buf).is_ok() {
-            let res = self.socket.recv(&mut buf)?;
-            let mut cur = Cursor::new(&buf[..res]);
+    // reads whatever the server sent and returns the events it produced, including
+    // the ones that came in during negotiate.
+    pub fn pump(&mut self) -> Result<Vec<ClientEvent>, Q2ProtoError> {
+        self.pump_packets()?;
+        Ok(self.session.take_events())
+    }
 
-            // println!("RECV");
-            // hexdump::hexdump(&buf[..res]);
+    // keeps pumping and yields events one by one. ends once the connection is gone.
+    pub fn poll_events(&mut self) -> ClientEvents<'_> {
+        ClientEvents {
+            client: self,
+            pending: VecDeque::new(),
+            done: false,
+        }
+    }
 
-            if self.chan.process(&mut cur) {
-                self.parse_command(&mut cur)?;
-            }
+    // every event also gets sent here, so another thread can consume them.
+    pub fn set_event_sender(&mut self, sender: Sender<ClientEvent>) {
+        self.session.set_event_sender(Some(sender));
+    }
 
-            let should_nop = self.last_msg_sent_time.elapsed() > Duration::from_secs(2);
+    pub fn clear_event_sender(&mut self) {
+        self.session.set_event_sender(None);
+    }
 
-            if should_nop {
-                self.send_nop();
-                self.last_msg_sent_time = Instant::now();
-            }
+    fn pump_packets(&mut self) -> Result<(), Q2ProtoError> {
+        if !self.session.is_live() {
+            return Err(Q2ProtoError::NotConnected);
+        }
 
-            let data = [0u8; 0];
-            if self.chan.should_transmit() {
-                let transmit_cursor = self.chan.transmit(&data);
-                let transmit_data_size = transmit_cursor.position() as usize;
-                let transmit_data = &transmit_cursor.into_inner()[..transmit_data_size];
+        let mut buf = [0u8; MAX_WRITEABLE_SIZE];
 
-                // println!("SENT");
-                // hexdump::hexdump(&transmit_data);
-                self.socket.send(transmit_data)?;
-                self.last_msg_sent_time = Instant::now();
+        // don't sleep through a resend or a keepalive.
+        let mut wait = self.read_timeout.get();
+        if let Some(deadline) = self.session.poll_timeout() {
+            wait = wait.min(deadline.saturating_duration_since(Instant::now()));
+        }
+        self.socket
+            .set_read_timeout(Some(wait.max(MIN_READ_WAIT)))?;
+
+        // wait for the first one, then take whatever else is already there. otherwise
+        // a busy server would never let us return.
+        match self.socket.recv(&mut buf).map_err(Q2ProtoError::from) {
+            Ok(res) => {
+                self.handle_datagram(&buf[..res])?;
+
+                self.socket.set_nonblocking(true)?;
+                let drained = self.drain(&mut buf);
+                self.socket.set_nonblocking(false)?;
+                drained?;
             }
+            // nothing came in, there's still the timeout to handle.
+            Err(Q2ProtoError::Timeout) => {}
+            Err(e) => return Err(e),
         }
 
-        Ok(())
+        // this is where a dead server gets noticed.
+        self.session.handle_timeout(Instant::now());
+        self.flush()
     }
 
-    fn send_nop(&mut self) -> Option<()> {
-        self.chan
-            .message
-            .cur
-            .write_u8(ClientToServerOps::Nop as u8)
-            .ok()
+    // everything that's already waiting on the non-blocking socket.
+    fn drain(&mut self, buf: &mut [u8]) -> Result<(), Q2ProtoError> {
+        loop {
+            match self.socket.recv(buf).map_err(Q2ProtoError::from) {
+                Ok(res) => self.handle_datagram(&buf[..res])?,
+                Err(Q2ProtoError::Timeout) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
     }
 
-    fn check_stuffcmd(&mut self, stuff_text: &[u8]) -> bool {
-        let cmd_list = stuff_text.split(|f| *f == b'\n');
-
-        for cmd in cmd_list {
-            let stuffcmd_head = b"cmd \x7fc";
-            let bytes: &[u8] = cmd;
-
-            // Let the protocol (us) handle it.
-            // The way Q2 does is by actually expanding the variables but we do the minimum work possible.
-            if bytes.starts_with(stuffcmd_head) {
-                let cmd_slice = &bytes[7..];
-                let cmd_str_opt = String::from_utf8(cmd_slice.to_vec());
-                if cmd_str_opt.is_err() {
-                    return false;
-                }
+    fn handle_datagram(&mut self, data: &[u8]) -> Result<(), Q2ProtoError> {
+        let now = Instant::now();
+        let handled = self.session.handle_datagram(data, now);
+        self.write_recording()?;
+        handled?;
+        self.session.handle_timeout(now);
+        self.flush()
+    }
 
-                let cmd_str = cmd_str_opt.unwrap();
-                println!("cmd: {cmd_str}");
-                if cmd_str.starts_with("version") {
-                    self.send_result_command(format!("version \"{}\"", &self.version).as_ref());
-                } else if cmd_str.starts_with("actoken") {
-                    self.send_result_command("actoken");
-                }
+    fn write_recording(&mut self) -> Result<(), Q2ProtoError> {
+        if let Some(demo) = self.recording.as_mut() {
+            while let Some(msg) = self.session.poll_message() {
+                demo.write_block(&msg)?;
             }
+        }
 
-            let changing_cmd = b"changing";
-            let precache_cmd = b"precache";
+        Ok(())
+    }
 
-            if bytes.starts_with(precache_cmd) {
-                // cmd_precache_f
-                // throw an event that requests a precache?
-                self.last_precache_value =
-                    String::from_utf8(bytes[9..].to_vec()).map_or(0, |f| f.parse().unwrap_or(0));
+    fn flush(&mut self) -> Result<(), Q2ProtoError> {
+        while let Some(data) = self.session.poll_transmit() {
+            self.socket.send_to(&data, &self.server_address)?;
+        }
 
-                let msg = format!("begin {}", self.last_precache_value);
-                self.send_command(msg.as_ref());
+        Ok(())
+    }
+}
 
-                self.last_msg_sent_time = Instant::now();
-            } else if bytes.starts_with(changing_cmd) {
-                // cmd_changing_f
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use demo::{DemoReader, MAX_MSGLEN};
+    use objects::{CS_GENERAL, CS_NAME};
+    use session::tests::{connected, server_packet, serverdata};
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // a Vec<u8> we can still look at after the client is done writing to it.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
         }
+    }
 
-        true // Pass it to the client
+    fn frame(server_frame: i32) -> ClientEvent {
+        ClientEvent::Frame(Box::new(FrameMessage {
+            server_frame,
+            delta_frame: server_frame - 1,
+            suppress_count: 0,
+            area_bits: vec![1],
+            player_state: Default::default(),
+            entities: vec![DeltaEntity {
+                number: 1,
+                origin0: Some(server_frame as f32),
+                ..Default::default()
+            }],
+        }))
     }
 
-    fn send_result_command(&mut self, cmd: &str) -> Option<()> {
-        if !self.connected {
-            return None;
+    #[test]
+    fn records_what_it_gets() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let mut client = Q2ProtoClient::new(&addr, "127.0.0.1", 0, "test").unwrap();
+        client.session = connected(Instant::now());
+
+        // a level, with enough in it that the header takes a few blocks.
+        let mut configstrings = vec![ClientEvent::ConfigString(CS_NAME, b"The Edge".to_vec())];
+        for i in 0..20 {
+            configstrings.push(ClientEvent::ConfigString(CS_GENERAL + i, vec![b'x'; 200]));
         }
+        let baselines: Vec<ClientEvent> = (1..=3)
+            .map(|number| {
+                ClientEvent::DeltaEntity(DeltaEntity {
+                    number,
+                    model_index: Some(number as u8),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let serverdata_evt = ClientEvent::ServerData(serverdata());
+        assert!(client.start_recording_to(SharedBuf::default()).is_err());
+        client
+            .handle_datagram(&server_packet(1, &[serverdata_evt]))
+            .unwrap();
+        client
+            .handle_datagram(&server_packet(2, &configstrings))
+            .unwrap();
+        client
+            .handle_datagram(&server_packet(3, &baselines))
+            .unwrap();
+        client
+            .handle_datagram(&server_packet(
+                4,
+                &[ClientEvent::StuffText(b"precache 7\n".to_vec())],
+            ))
+            .unwrap();
+        assert_eq!(client.state(), ConnectionState::Active);
+
+        let buf = SharedBuf::default();
+        client.start_recording_to(buf.clone()).unwrap();
+        let frames = [frame(10), frame(11), frame(12)];
+        for (seq, frame) in (5..).zip(&frames) {
+            client
+                .handle_datagram(&server_packet(seq, std::slice::from_ref(frame)))
+                .unwrap();
+        }
+        client.stop_recording().unwrap();
+        assert!(!client.is_recording());
+
+        let demo = buf.0.lock().unwrap().clone();
+        let mut reader = DemoReader::new(&demo[..]);
+        let mut blocks = 0;
+        while let Some(block) = reader.next_block().unwrap() {
+            assert!(block.len() <= MAX_MSGLEN);
+            blocks += 1;
+        }
+        assert!(blocks > 1 + frames.len());
+
+        // what cl_record would have written, then the frames as they came.
+        let mut expected = vec![ClientEvent::ServerData(ServerDataMessage {
+            attract_loop: 1,
+            ..serverdata()
+        })];
+        expected.extend(configstrings);
+        expected.extend(baselines);
+        expected.push(ClientEvent::StuffText(b"precache\n".to_vec()));
+        expected.extend(frames);
+
+        let events: Vec<ClientEvent> = DemoReader::new(&demo[..])
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(events, expected);
+    }
 
-        self.chan
-            .message
-            .cur
-            .write_u8(ClientToServerOps::StringCmd as u8)
-            .ok()?;
-        self.chan.message.cur.write_all(b"\x7fc ").ok()?;
-        self.chan.message.write_string(cmd)?;
+    #[test]
+    fn oob_replies_skip_strays() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let stand_in = thread::spawn(move || {
+            let mut buf = [0u8; 1500];
+            let (len, client) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(connectionless_payload(&buf[..len]), Some(&b"status"[..]));
+
+            // someone else, then a netchan packet, then the answer.
+            let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+            stranger
+                .send_to(&oob_packet(b"print\n\\hostname\\fake\n"), client)
+                .unwrap();
+            server.send_to(&[1, 0, 0, 0, 0, 0, 0, 0], client).unwrap();
+            server
+                .send_to(&oob_packet(b"print\n\\hostname\\real\n"), client)
+                .unwrap();
+        });
+
+        let client = Q2ProtoClient::new(&addr.to_string(), "127.0.0.1", 0, "test").unwrap();
+        let status = client.status().unwrap();
+        assert_eq!(status.info["hostname"], "real");
+        stand_in.join().unwrap();
+    }
 
-        Some(())
+    #[test]
+    fn events_reach_the_sender_too() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let mut client = Q2ProtoClient::new(&addr, "127.0.0.1", 0, "test").unwrap();
+        client.session = connected(Instant::now());
+        client.socket.connect(&addr).unwrap();
+        let client_addr = client.socket.local_addr().unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        client.set_event_sender(tx);
+
+        let hi = [ClientEvent::Print(PrintLevel::HIGH, b"hi\n".to_vec())];
+        server.send_to(&server_packet(1, &hi), client_addr).unwrap();
+        assert_eq!(client.pump().unwrap(), hi);
+        assert_eq!(rx.try_recv().unwrap(), hi[0]);
+
+        let bye = [ClientEvent::Print(PrintLevel::HIGH, b"bye\n".to_vec())];
+        server
+            .send_to(&server_packet(2, &bye), client_addr)
+            .unwrap();
+        assert_eq!(client.poll_events().next().unwrap().unwrap(), bye[0]);
+        assert_eq!(rx.try_recv().unwrap(), bye[0]);
+        assert!(rx.try_recv().is_err());
     }
 }
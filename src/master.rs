@@ -0,0 +1,253 @@
+use super::error::Q2ProtoError;
+use super::session::{connectionless_payload, oob_packet};
+use super::status::{ServerInfo, ServerStatus};
+use super::{ProtocolVersion, Q2ProtoClient, DEFAULT_READ_TIMEOUT};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+pub const DEFAULT_MASTER_PORT: u16 = 27900;
+// big lists come in more than one packet. once they stop coming for this long, we're done.
+const MORE_SERVERS_WAIT: Duration = Duration::from_millis(500);
+// how many servers get asked at once when fanning out.
+const MAX_PARALLEL_QUERIES: usize = 32;
+
+// talks to a q2 master server to get the server list.
+pub struct MasterClient {
+    socket: UdpSocket,
+    master_address: SocketAddr,
+    read_timeout: Duration,
+}
+
+impl MasterClient {
+    pub fn new(
+        master: &str,
+        bind_addr: &str,
+        bind_port: u16,
+    ) -> Result<MasterClient, Q2ProtoError> {
+        let socket =
+            UdpSocket::bind(format!("{}:{}", bind_addr, bind_port)).map_err(Q2ProtoError::Bind)?;
+        let master_address = master.to_socket_addrs()?.next().ok_or_else(|| {
+            Q2ProtoError::Io(std::io::Error::from(std::io::ErrorKind::AddrNotAvailable))
+        })?;
+
+        Ok(MasterClient {
+            socket,
+            master_address,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        })
+    }
+
+    // how long to wait for the first packet of the list.
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = timeout;
+    }
+
+    // every server the master knows about.
+    pub fn query(&self) -> Result<Vec<SocketAddrV4>, Q2ProtoError> {
+        self.socket
+            .send_to(&oob_packet(b"query"), self.master_address)?;
+
+        let mut servers = Vec::new();
+        let mut buf = [0u8; 1500];
+
+        self.socket.set_read_timeout(Some(self.read_timeout))?;
+        loop {
+            let (recv_bytes, addr) = match self.socket.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(e) => match Q2ProtoError::from(e) {
+                    // no more pieces of the list.
+                    Q2ProtoError::Timeout if !servers.is_empty() => break,
+                    e => return Err(e),
+                },
+            };
+            if addr != self.master_address {
+                continue; // not our master...
+            }
+
+            let payload = connectionless_payload(&buf[..recv_bytes]).ok_or_else(|| {
+                Q2ProtoError::MalformedResponse(
+                    String::from_utf8_lossy(&buf[..recv_bytes]).into_owned(),
+                )
+            })?;
+            let list = parse_servers(payload)?;
+            // an empty list is a complete answer.
+            if list.is_empty() && servers.is_empty() {
+                break;
+            }
+
+            servers.extend(list);
+            self.socket.set_read_timeout(Some(MORE_SERVERS_WAIT))?;
+        }
+
+        Ok(servers)
+    }
+}
+
+// `servers ` followed by 4 bytes of ip and 2 bytes of port (big endian) per server.
+pub fn parse_servers(payload: &[u8]) -> Result<Vec<SocketAddrV4>, Q2ProtoError> {
+    let records = payload
+        .strip_prefix(b"servers")
+        .map(|r| {
+            r.strip_prefix(b" ")
+                .or_else(|| r.strip_prefix(b"\n"))
+                .unwrap_or(r)
+        })
+        .ok_or_else(|| {
+            Q2ProtoError::MalformedResponse(String::from_utf8_lossy(payload).into_owned())
+        })?;
+
+    // a trailing partial record is junk, drop it.
+    Ok(records
+        .chunks_exact(6)
+        .map(|r| {
+            SocketAddrV4::new(
+                Ipv4Addr::new(r[0], r[1], r[2], r[3]),
+                u16::from_be_bytes([r[4], r[5]]),
+            )
+        })
+        .collect())
+}
+
+// the other way around, for the master side.
+pub fn write_servers(servers: &[SocketAddrV4]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(8 + servers.len() * 6);
+    payload.extend_from_slice(b"servers ");
+    for server in servers {
+        payload.extend_from_slice(&server.ip().octets());
+        payload.extend_from_slice(&server.port().to_be_bytes());
+    }
+
+    payload
+}
+
+// ask every server for its status, a few at a time. each one gets its own result.
+pub fn fetch_statuses(
+    servers: &[SocketAddrV4],
+    timeout: Duration,
+) -> Vec<(SocketAddrV4, Result<ServerStatus, Q2ProtoError>)> {
+    fan_out(servers, timeout, |cl| cl.status())
+}
+
+// same as fetch_statuses, but the cheaper `info` query.
+pub fn fetch_infos(
+    servers: &[SocketAddrV4],
+    proto: ProtocolVersion,
+    timeout: Duration,
+) -> Vec<(SocketAddrV4, Result<ServerInfo, Q2ProtoError>)> {
+    fan_out(servers, timeout, |cl| cl.info(proto))
+}
+
+fn fan_out<T, F>(
+    servers: &[SocketAddrV4],
+    timeout: Duration,
+    query: F,
+) -> Vec<(SocketAddrV4, Result<T, Q2ProtoError>)>
+where
+    T: Send,
+    F: Fn(&Q2ProtoClient) -> Result<T, Q2ProtoError> + Sync,
+{
+    let query_one = |server: &SocketAddrV4| {
+        let cl = Q2ProtoClient::new(&server.to_string(), "0.0.0.0", 0, "q2-proto")?;
+        cl.set_read_timeout(timeout)?;
+        query(&cl)
+    };
+
+    let mut results = Vec::with_capacity(servers.len());
+    for chunk in servers.chunks(MAX_PARALLEL_QUERIES) {
+        thread::scope(|s| {
+            let handles: Vec<_> = chunk
+                .iter()
+                .map(|server| (*server, s.spawn(|| query_one(server))))
+                .collect();
+
+            for (server, handle) in handles {
+                results.push((server, handle.join().expect("server query panicked")));
+            }
+        });
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::master_server::MasterServer;
+
+    fn servers(n: u16) -> Vec<SocketAddrV4> {
+        (0..n)
+            .map(|i| SocketAddrV4::new(Ipv4Addr::new(10, 0, (i >> 8) as u8, i as u8), 27910 + i))
+            .collect()
+    }
+
+    #[test]
+    fn servers_round_trip() {
+        let list = servers(3);
+        assert_eq!(parse_servers(&write_servers(&list)).unwrap(), list);
+
+        // some masters use a newline, and a cut off record is dropped.
+        let mut payload = b"servers\n".to_vec();
+        payload.extend_from_slice(&write_servers(&list)[8..]);
+        payload.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(parse_servers(&payload).unwrap(), list);
+
+        assert!(parse_servers(b"print\nno").is_err());
+    }
+
+    #[test]
+    fn query_stand_in() {
+        let stand_in = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = stand_in.local_addr().unwrap();
+        let list = servers(300);
+
+        let expected = list.clone();
+        let master = thread::spawn(move || {
+            let mut buf = [0u8; 1500];
+            let (len, from) = stand_in.recv_from(&mut buf).unwrap();
+            assert_eq!(connectionless_payload(&buf[..len]), Some(&b"query"[..]));
+
+            // too many for one packet.
+            for chunk in expected.chunks(200) {
+                stand_in
+                    .send_to(&oob_packet(&write_servers(chunk)), from)
+                    .unwrap();
+            }
+        });
+
+        let client = MasterClient::new(&addr.to_string(), "127.0.0.1", 0).unwrap();
+        assert_eq!(client.query().unwrap(), list);
+        master.join().unwrap();
+    }
+
+    #[test]
+    fn query_master_server() {
+        let mut master = MasterServer::new("127.0.0.1", 0).unwrap();
+        let addr = master.local_addr().unwrap();
+
+        let game = UdpSocket::bind("127.0.0.1:0").unwrap();
+        game.set_read_timeout(Some(DEFAULT_READ_TIMEOUT)).unwrap();
+        let game_addr = match game.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+
+        // it isn't listed until it answers the master's status.
+        game.send_to(&oob_packet(b"heartbeat\n"), addr).unwrap();
+        master.poll().unwrap();
+        assert!(master.servers().is_empty());
+
+        let mut buf = [0u8; 1500];
+        let (len, from) = game.recv_from(&mut buf).unwrap();
+        assert_eq!(connectionless_payload(&buf[..len]), Some(&b"status"[..]));
+        game.send_to(&oob_packet(b"print\n\\hostname\\test\n"), from)
+            .unwrap();
+        master.poll().unwrap();
+        assert_eq!(master.servers(), [game_addr]);
+
+        let master = thread::spawn(move || master.poll().unwrap());
+        let client = MasterClient::new(&addr.to_string(), "127.0.0.1", 0).unwrap();
+        assert_eq!(client.query().unwrap(), [game_addr]);
+        master.join().unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+use super::error::Q2ProtoError;
+use super::master::write_servers;
+use super::session::{connectionless_payload, oob_packet};
+use super::status::ServerStatus;
+use super::DEFAULT_READ_TIMEOUT;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+// servers heartbeat every 300 seconds. miss a couple of those and you're off the list.
+pub const DEFAULT_EXPIRE_AFTER: Duration = Duration::from_secs(900);
+// keeps each `servers` packet under the usual udp payload size.
+const SERVERS_PER_PACKET: usize = 230;
+// heartbeats are easy to spoof, so only this many servers may wait on our status at once,
+// and not for long.
+const MAX_PENDING: usize = 256;
+const PENDING_EXPIRE_AFTER: Duration = Duration::from_secs(30);
+// and we don't let anyone point our status queries at an address over and over.
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_PROBES_PER_SECOND: u32 = 50;
+
+struct ServerEntry {
+    last_heartbeat: Instant,
+    last_probe: Option<Instant>,
+    // filled in once the server answers our `status`. until then it isn't listed.
+    status: Option<ServerStatus>,
+}
+
+// the master side: game servers heartbeat to it, clients `query` it for the list.
+pub struct MasterServer {
+    socket: UdpSocket,
+    servers: HashMap<SocketAddrV4, ServerEntry>,
+    expire_after: Duration,
+    // the second we're counting probes for, and how many went out in it.
+    probe_window: (Instant, u32),
+}
+
+impl MasterServer {
+    pub fn new(bind_addr: &str, bind_port: u16) -> Result<MasterServer, Q2ProtoError> {
+        let socket =
+            UdpSocket::bind(format!("{}:{}", bind_addr, bind_port)).map_err(Q2ProtoError::Bind)?;
+        socket.set_read_timeout(Some(DEFAULT_READ_TIMEOUT))?;
+
+        Ok(MasterServer {
+            socket,
+            servers: HashMap::new(),
+            expire_after: DEFAULT_EXPIRE_AFTER,
+            probe_window: (Instant::now(), 0),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Q2ProtoError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn set_expire_after(&mut self, expire_after: Duration) {
+        self.expire_after = expire_after;
+    }
+
+    // the servers that answered our status query, which is what `query` hands out.
+    pub fn servers(&self) -> Vec<SocketAddrV4> {
+        self.servers
+            .iter()
+            .filter(|(_, entry)| entry.status.is_some())
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    // what the server said the last time we asked.
+    pub fn server_status(&self, addr: &SocketAddrV4) -> Option<&ServerStatus> {
+        self.servers.get(addr)?.status.as_ref()
+    }
+
+    // handle whatever comes in within the read timeout, then drop stale servers. a peer we
+    // couldn't answer shouldn't take the master down, so that comes back as Ok, with who it was.
+    pub fn poll(&mut self) -> Result<Option<(SocketAddr, Q2ProtoError)>, Q2ProtoError> {
+        let mut buf = [0u8; 1500];
+
+        let res = match self.socket.recv_from(&mut buf) {
+            Ok((recv_bytes, from)) => Ok(self
+                .handle_packet(&buf[..recv_bytes], from, Instant::now())
+                .err()
+                .map(|e| (from, e))),
+            // some peer we sent to isn't there. windows tells us on the next recv.
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused
+                ) =>
+            {
+                Ok(None)
+            }
+            // nothing came in, that's fine.
+            Err(e) => match Q2ProtoError::from(e) {
+                Q2ProtoError::Timeout => Ok(None),
+                e => Err(e),
+            },
+        };
+
+        self.expire(Instant::now());
+        res
+    }
+
+    // never returns unless the socket breaks. errors with single peers are dropped, use
+    // `poll` to see them.
+    pub fn run(&mut self) -> Result<(), Q2ProtoError> {
+        loop {
+            self.poll()?;
+        }
+    }
+
+    pub fn handle_packet(
+        &mut self,
+        data: &[u8],
+        from: SocketAddr,
+        now: Instant,
+    ) -> Result<(), Q2ProtoError> {
+        // the server list format only has room for ipv4.
+        let from = match from {
+            SocketAddr::V4(from) => from,
+            SocketAddr::V6(_) => return Ok(()),
+        };
+        let payload = match connectionless_payload(data) {
+            Some(payload) => payload,
+            None => return Ok(()),
+        };
+
+        if payload.starts_with(b"heartbeat") {
+            self.heartbeat(from, now)?;
+        } else if payload.starts_with(b"ping") {
+            // only the status probe is rate limited, the server still wants its ack.
+            if self.heartbeat(from, now)? {
+                self.send_to(from, b"ack")?;
+            }
+        } else if payload.starts_with(b"shutdown") {
+            self.servers.remove(&from);
+        } else if payload.starts_with(b"print\n") {
+            // hopefully the answer to our status query.
+            if let Some(entry) = self.servers.get_mut(&from) {
+                if let Ok(status) = ServerStatus::parse(payload) {
+                    entry.status = Some(status);
+                }
+            }
+        } else if payload.starts_with(b"query") {
+            self.send_server_list(from)?;
+        }
+
+        Ok(())
+    }
+
+    // forget servers that stopped heartbeating, or never answered.
+    pub fn expire(&mut self, now: Instant) {
+        let expire_after = self.expire_after;
+        self.servers.retain(|_, entry| {
+            let expire_after = match entry.status {
+                Some(_) => expire_after,
+                None => PENDING_EXPIRE_AFTER.min(expire_after),
+            };
+            now.duration_since(entry.last_heartbeat) < expire_after
+        });
+    }
+
+    // keeps the server on the list, or puts it on as pending. false if there's no room for it.
+    fn heartbeat(&mut self, from: SocketAddrV4, now: Instant) -> Result<bool, Q2ProtoError> {
+        if !self.servers.contains_key(&from) {
+            let pending = self.servers.values().filter(|e| e.status.is_none()).count();
+            if pending >= MAX_PENDING {
+                return Ok(false);
+            }
+        }
+
+        let entry = self.servers.entry(from).or_insert(ServerEntry {
+            last_heartbeat: now,
+            last_probe: None,
+            status: None,
+        });
+        entry.last_heartbeat = now;
+
+        self.probe(from, now)?;
+        Ok(true)
+    }
+
+    // don't take the heartbeat's word for it, ask the server ourselves. false if we didn't
+    // get to ask.
+    fn probe(&mut self, to: SocketAddrV4, now: Instant) -> Result<bool, Q2ProtoError> {
+        let asked_lately = self
+            .servers
+            .get(&to)
+            .and_then(|entry| entry.last_probe)
+            .is_some_and(|at| now.duration_since(at) < PROBE_INTERVAL);
+        if asked_lately || !self.take_probe(now) {
+            return Ok(false);
+        }
+
+        if let Some(entry) = self.servers.get_mut(&to) {
+            entry.last_probe = Some(now);
+        }
+        self.send_to(to, b"status")?;
+        Ok(true)
+    }
+
+    // whether there's a probe left in this second.
+    fn take_probe(&mut self, now: Instant) -> bool {
+        let (start, count) = &mut self.probe_window;
+        if now.duration_since(*start) >= Duration::from_secs(1) {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= MAX_PROBES_PER_SECOND {
+            return false;
+        }
+
+        *count += 1;
+        true
+    }
+
+    fn send_server_list(&self, to: SocketAddrV4) -> Result<(), Q2ProtoError> {
+        let servers = self.servers();
+        if servers.is_empty() {
+            return self.send_to(to, &write_servers(&[]));
+        }
+
+        for chunk in servers.chunks(SERVERS_PER_PACKET) {
+            self.send_to(to, &write_servers(chunk))?;
+        }
+
+        Ok(())
+    }
+
+    fn send_to(&self, to: SocketAddrV4, msg: &[u8]) -> Result<(), Q2ProtoError> {
+        self.socket.send_to(&oob_packet(msg), to)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn peer(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)
+    }
+
+    #[test]
+    fn pending_is_capped() {
+        let mut master = MasterServer::new("127.0.0.1", 0).unwrap();
+        let now = Instant::now();
+
+        for port in 0..MAX_PENDING as u16 + 10 {
+            master
+                .handle_packet(&oob_packet(b"heartbeat\n"), peer(40000 + port).into(), now)
+                .unwrap();
+        }
+        assert_eq!(master.servers.len(), MAX_PENDING);
+
+        // the ones that never answer go away long before the real servers would.
+        master.expire(now + PENDING_EXPIRE_AFTER);
+        assert!(master.servers.is_empty());
+    }
+
+    #[test]
+    fn probes_are_rate_limited() {
+        let mut master = MasterServer::new("127.0.0.1", 0).unwrap();
+        let now = Instant::now();
+
+        for port in 0..MAX_PROBES_PER_SECOND as u16 * 2 {
+            assert!(master.heartbeat(peer(40000 + port), now).unwrap());
+        }
+        let probed = master
+            .servers
+            .values()
+            .filter(|entry| entry.last_probe.is_some())
+            .count();
+        assert_eq!(probed, MAX_PROBES_PER_SECOND as usize);
+
+        // one address only gets asked once per PROBE_INTERVAL.
+        let later = now + Duration::from_secs(2);
+        assert!(master.probe(peer(50000), later).unwrap());
+        master.heartbeat(peer(50000), later).unwrap();
+        assert!(!master.probe(peer(50000), later).unwrap());
+        assert!(master.probe(peer(50000), later + PROBE_INTERVAL).unwrap());
+    }
+
+    #[test]
+    fn pings_are_acked_even_without_a_probe() {
+        let mut master = MasterServer::new("127.0.0.1", 0).unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(DEFAULT_READ_TIMEOUT)).unwrap();
+        let from = server.local_addr().unwrap();
+        let now = Instant::now();
+
+        let mut buf = [0u8; 1500];
+        let mut recv = || {
+            let len = server.recv(&mut buf).unwrap();
+            connectionless_payload(&buf[..len]).unwrap().to_vec()
+        };
+
+        master
+            .handle_packet(&oob_packet(b"ping\n"), from, now)
+            .unwrap();
+        assert_eq!(recv(), b"status");
+        assert_eq!(recv(), b"ack");
+
+        // asked it too recently to ask again, but it still gets its ack.
+        master
+            .handle_packet(&oob_packet(b"ping\n"), from, now)
+            .unwrap();
+        assert_eq!(recv(), b"ack");
+    }
+}
@@ -0,0 +1,90 @@
+use super::error::Q2ProtoError;
+use super::objects::{
+    parse_baseline, parse_configstring, parse_frame, parse_inventory, parse_muzzle_flash,
+    parse_muzzle_flash2, parse_print, parse_serverdata, parse_sound, parse_string,
+    parse_temp_entity, skip_download, write_baseline, write_configstring, write_frame,
+    write_inventory, write_muzzle_flash, write_muzzle_flash2, write_print, write_serverdata,
+    write_sound, write_string, write_stufftext, write_temp_entity,
+};
+use super::{ClientEvent, ServerToClientOps};
+use byteorder::ReadBytesExt;
+use std::io::Cursor;
+
+// the op byte has already been read. Ok(None) means the op was read fine but there's
+// nothing to tell anyone about.
+pub fn parse_op<T: AsRef<[u8]>>(
+    cmd_byte: u8,
+    cursor: &mut Cursor<T>,
+) -> Result<Option<ClientEvent>, Q2ProtoError> {
+    let op = match ServerToClientOps::from(cmd_byte) {
+        ServerToClientOps::MuzzleFlash => parse_muzzle_flash(cursor)?,
+        ServerToClientOps::MuzzleFlash2 => parse_muzzle_flash2(cursor)?,
+        ServerToClientOps::TempEntity => parse_temp_entity(cursor)?,
+        ServerToClientOps::Layout => ClientEvent::Layout(parse_string(cursor)),
+        ServerToClientOps::Inventory => parse_inventory(cursor)?,
+        ServerToClientOps::Nop => return Ok(None),
+        ServerToClientOps::Disconnect => ClientEvent::Disconnect,
+        ServerToClientOps::Reconnect => ClientEvent::Reconnect,
+        ServerToClientOps::Sound => parse_sound(cursor)?,
+        ServerToClientOps::Print => parse_print(cursor)?,
+        ServerToClientOps::StuffText => ClientEvent::StuffText(parse_string(cursor)),
+        ServerToClientOps::ServerData => parse_serverdata(cursor)?,
+        ServerToClientOps::ConfigString => parse_configstring(cursor)?,
+        ServerToClientOps::SpawnBaseline => parse_baseline(cursor)?,
+        ServerToClientOps::CenterPrint => ClientEvent::CenterPrint(parse_string(cursor)),
+        ServerToClientOps::Download => {
+            skip_download(cursor)?;
+            return Ok(None);
+        }
+        ServerToClientOps::Frame => parse_frame(cursor)?,
+        // playerinfo and packetentities only ever come as part of a frame. the rest are
+        // r1q2/q2pro, which we don't speak.
+        _ => return Err(Q2ProtoError::UnknownOp(cmd_byte)),
+    };
+
+    Ok(Some(op))
+}
+
+// everything in one server message, with no netchan header in front. demos are made of these.
+pub fn parse_message(data: &[u8]) -> Result<Vec<ClientEvent>, Q2ProtoError> {
+    let mut cursor = Cursor::new(data);
+    let mut events = Vec::new();
+
+    while let Ok(cmd_byte) = cursor.read_u8() {
+        if let Some(evt) = parse_op(cmd_byte, &mut cursor)? {
+            events.push(evt);
+        }
+    }
+
+    Ok(events)
+}
+
+// the other way around: the event as the server would have sent it.
+pub fn write_event(buf: &mut Vec<u8>, evt: &ClientEvent) {
+    match evt {
+        ClientEvent::Disconnect => buf.push(ServerToClientOps::Disconnect as u8),
+        ClientEvent::Reconnect => buf.push(ServerToClientOps::Reconnect as u8),
+        ClientEvent::Print(level, text) => write_print(buf, *level, text),
+        ClientEvent::StuffText(text) => write_stufftext(buf, text),
+        ClientEvent::CenterPrint(text) => {
+            buf.push(ServerToClientOps::CenterPrint as u8);
+            write_string(buf, text);
+        }
+        ClientEvent::ServerData(serverdata) => write_serverdata(buf, serverdata),
+        ClientEvent::ConfigString(index, value) => write_configstring(buf, *index, value),
+        // outside of frames, entities only come as baselines.
+        ClientEvent::DeltaEntity(ent) => write_baseline(buf, ent),
+        ClientEvent::Frame(frame) => write_frame(buf, frame),
+        ClientEvent::MuzzleFlash(flash) => write_muzzle_flash(buf, flash),
+        ClientEvent::MuzzleFlash2(entity, flash) => write_muzzle_flash2(buf, *entity, *flash),
+        ClientEvent::TempEntity(kind, data) => write_temp_entity(buf, *kind, data),
+        ClientEvent::Sound(sound) => write_sound(buf, sound),
+        ClientEvent::Layout(layout) => {
+            buf.push(ServerToClientOps::Layout as u8);
+            write_string(buf, layout);
+        }
+        ClientEvent::Inventory(inventory) => write_inventory(buf, inventory),
+        // ours, not the server's. chat is already in the print it came from.
+        ClientEvent::Chat(_) | ClientEvent::Timeout => {}
+    }
+}
@@ -0,0 +1,714 @@
+use super::error::Q2ProtoError;
+use super::message::parse_op;
+use super::objects::{
+    parse_angle16, parse_char_vec, parse_coord, parse_delta_entity, parse_entity_bits,
+    parse_string, DeltaEntity, PlayerState, PrintLevel, SoundMessage, CS_PLAYERSKINS,
+    MAX_CONFIGSTRINGS, SND_ATTENUATION, SND_OFFSET, SND_VOLUME,
+};
+use super::text::decode;
+use super::ClientEvent;
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, Cursor, ErrorKind, Read};
+use std::path::Path;
+
+// what every .mvd2 starts with.
+pub const MVD_MAGIC: &[u8; 4] = b"MVD2";
+pub const PROTOCOL_VERSION_MVD: i32 = 37;
+// the minor versions we can read. later ones changed the encodings for bigger limits.
+pub const PROTOCOL_VERSION_MVD_MINIMUM: u16 = 2009;
+pub const PROTOCOL_VERSION_MVD_DEFAULT: u16 = 2010;
+// ends the player list in a frame.
+const CLIENTNUM_NONE: u8 = 255;
+// the low bits of the op byte are the op, the top three are extra bits for it.
+const SVCMD_BITS: u8 = 5;
+const SVCMD_MASK: u8 = (1 << SVCMD_BITS) - 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MvdOps {
+    Bad = 0,
+    Nop,
+    Disconnect,
+    Reconnect,
+    ServerData,
+    ConfigString,
+    Frame,
+    FrameNoDelta,
+    Unicast,
+    UnicastReliable,
+    MulticastAll,
+    MulticastPvs,
+    MulticastPhs,
+    MulticastAllReliable,
+    MulticastPvsReliable,
+    MulticastPhsReliable,
+    Sound,
+    Print,
+    StuffText,
+    Invalid = -1,
+}
+
+impl From<u8> for MvdOps {
+    fn from(b: u8) -> Self {
+        match b {
+            0 => MvdOps::Bad,
+            1 => MvdOps::Nop,
+            2 => MvdOps::Disconnect,
+            3 => MvdOps::Reconnect,
+            4 => MvdOps::ServerData,
+            5 => MvdOps::ConfigString,
+            6 => MvdOps::Frame,
+            7 => MvdOps::FrameNoDelta,
+            8 => MvdOps::Unicast,
+            9 => MvdOps::UnicastReliable,
+            10 => MvdOps::MulticastAll,
+            11 => MvdOps::MulticastPvs,
+            12 => MvdOps::MulticastPhs,
+            13 => MvdOps::MulticastAllReliable,
+            14 => MvdOps::MulticastPvsReliable,
+            15 => MvdOps::MulticastPhsReliable,
+            16 => MvdOps::Sound,
+            17 => MvdOps::Print,
+            18 => MvdOps::StuffText,
+            _ => MvdOps::Invalid,
+        }
+    }
+}
+
+// which parts of a player state are in an mvd frame. not the same bits as svc_playerinfo.
+pub enum PacketPlayerStateBits {
+    MTYPE = (1 << 0),
+    MORIGIN = (1 << 1),
+    MORIGIN2 = (1 << 2),
+    VIEWOFFSET = (1 << 3),
+    VIEWANGLES = (1 << 4),
+    VIEWANGLE2 = (1 << 5),
+    KICKANGLES = (1 << 6),
+    BLEND = (1 << 7),
+    FOV = (1 << 8),
+    WEAPONINDEX = (1 << 9),
+    WEAPONFRAME = (1 << 10),
+    GUNOFFSET = (1 << 11),
+    GUNANGLES = (1 << 12),
+    RDFLAGS = (1 << 13),
+    STATS = (1 << 14),
+    REMOVE = (1 << 15),
+}
+
+fn has(bits: u16, bit: PacketPlayerStateBits) -> bool {
+    bits & (bit as u16) != 0
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MvdServerData {
+    pub protocol: i32,
+    pub minor_version: u16,
+    pub server_count: i32,
+    pub gamedir: String,
+    // the slot of the dummy client the server recorded through.
+    pub client_num: i16,
+    // MVF_ bits.
+    pub flags: u8,
+}
+
+// a player in a frame, after the frame's changes were applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MvdPlayer {
+    pub number: u8,
+    pub state: PlayerState,
+    // false once they left.
+    pub in_use: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MvdFrame {
+    pub portal_bits: Vec<u8>,
+    // only the ones that changed. the states are complete, not deltas.
+    pub players: Vec<MvdPlayer>,
+    // same here. `remove` is set on the ones that went away.
+    pub entities: Vec<DeltaEntity>,
+}
+
+#[derive(Clone, Debug)]
+pub enum MvdEvent {
+    // the configstrings and baseline frame that come with it follow as their own events.
+    ServerData(MvdServerData),
+    ConfigString(u16, Vec<u8>),
+    Frame(Box<MvdFrame>),
+    // messages that went to just the one player.
+    Unicast {
+        client: u8,
+        reliable: bool,
+        events: Vec<ClientEvent>,
+    },
+    // messages to everyone (in range of `leaf`, if there is one): temp entities, flashes...
+    Multicast {
+        leaf: Option<u16>,
+        reliable: bool,
+        events: Vec<ClientEvent>,
+    },
+    Sound(SoundMessage),
+    Print(PrintLevel, Vec<u8>),
+    StuffText(Vec<u8>),
+}
+
+// one player's point of view at the current point of the demo.
+pub struct PlayerView<'a> {
+    pub number: u8,
+    pub name: Option<String>,
+    pub player_state: &'a PlayerState,
+    pub entities: &'a HashMap<i16, DeltaEntity>,
+}
+
+// everything an mvd has told us so far. the frames are deltas, so the messages have to go
+// through this in order.
+#[derive(Default)]
+pub struct MvdState {
+    serverdata: Option<MvdServerData>,
+    configstrings: HashMap<u16, Vec<u8>>,
+    players: HashMap<u8, PlayerState>,
+    entities: HashMap<i16, DeltaEntity>,
+}
+
+impl MvdState {
+    pub fn new() -> MvdState {
+        MvdState::default()
+    }
+
+    pub fn serverdata(&self) -> Option<&MvdServerData> {
+        self.serverdata.as_ref()
+    }
+
+    pub fn configstring(&self, index: u16) -> Option<&[u8]> {
+        self.configstrings.get(&index).map(|f| f.as_slice())
+    }
+
+    pub fn configstrings(&self) -> &HashMap<u16, Vec<u8>> {
+        &self.configstrings
+    }
+
+    pub fn entities(&self) -> &HashMap<i16, DeltaEntity> {
+        &self.entities
+    }
+
+    // the slots that have someone in them, in order.
+    pub fn players(&self) -> Vec<u8> {
+        let mut players: Vec<u8> = self.players.keys().copied().collect();
+        players.sort();
+        players
+    }
+
+    pub fn view(&self, number: u8) -> Option<PlayerView<'_>> {
+        Some(PlayerView {
+            number,
+            name: self.player_name(number),
+            player_state: self.players.get(&number)?,
+            entities: &self.entities,
+        })
+    }
+
+    // the name part of the `name\model/skin` playerskin configstring.
+    pub fn player_name(&self, number: u8) -> Option<String> {
+        let skin = self.configstring(CS_PLAYERSKINS + number as u16)?;
+        let name = skin.split(|b| *b == b'\\').next().unwrap_or(skin);
+        Some(decode(name))
+    }
+
+    // one mvd message, no length in front.
+    pub fn parse_message(&mut self, data: &[u8]) -> Result<Vec<MvdEvent>, Q2ProtoError> {
+        let mut cur = Cursor::new(data);
+        let mut events = Vec::new();
+
+        while let Ok(cmd_byte) = cur.read_u8() {
+            let extra_bits = cmd_byte >> SVCMD_BITS;
+            let op = MvdOps::from(cmd_byte & SVCMD_MASK);
+            let truncated = || Q2ProtoError::MvdTruncated(op);
+
+            match op {
+                MvdOps::Nop => {}
+                MvdOps::ServerData => {
+                    self.parse_serverdata(&mut cur, extra_bits, &mut events)
+                        .ok_or_else(truncated)??;
+                }
+                MvdOps::ConfigString => {
+                    let index = cur.read_u16::<LittleEndian>().map_err(|_| truncated())?;
+                    let value = parse_string(&mut cur);
+                    self.configstrings.insert(index, value.clone());
+                    events.push(MvdEvent::ConfigString(index, value));
+                }
+                MvdOps::Frame => {
+                    let frame = self.parse_frame(&mut cur).ok_or_else(truncated)?;
+                    events.push(MvdEvent::Frame(Box::new(frame)));
+                }
+                MvdOps::Unicast | MvdOps::UnicastReliable => {
+                    let len = read_length(&mut cur, extra_bits).ok_or_else(truncated)?;
+                    let client = cur.read_u8().map_err(|_| truncated())?;
+                    let data = read_data(&mut cur, len).ok_or_else(truncated)?;
+
+                    events.push(MvdEvent::Unicast {
+                        client,
+                        reliable: op == MvdOps::UnicastReliable,
+                        events: parse_embedded(&data),
+                    });
+                }
+                MvdOps::MulticastAll
+                | MvdOps::MulticastPvs
+                | MvdOps::MulticastPhs
+                | MvdOps::MulticastAllReliable
+                | MvdOps::MulticastPvsReliable
+                | MvdOps::MulticastPhsReliable => {
+                    let len = read_length(&mut cur, extra_bits).ok_or_else(truncated)?;
+                    let leaf = match op {
+                        MvdOps::MulticastAll | MvdOps::MulticastAllReliable => None,
+                        _ => Some(cur.read_u16::<LittleEndian>().map_err(|_| truncated())?),
+                    };
+                    let data = read_data(&mut cur, len).ok_or_else(truncated)?;
+
+                    events.push(MvdEvent::Multicast {
+                        leaf,
+                        reliable: matches!(
+                            op,
+                            MvdOps::MulticastAllReliable
+                                | MvdOps::MulticastPvsReliable
+                                | MvdOps::MulticastPhsReliable
+                        ),
+                        events: parse_embedded(&data),
+                    });
+                }
+                MvdOps::Sound => {
+                    let sound = parse_mvd_sound(&mut cur).ok_or_else(truncated)?;
+                    events.push(MvdEvent::Sound(sound));
+                }
+                MvdOps::Print => {
+                    let level = cur.read_u8().map_err(|_| truncated())?;
+                    events.push(MvdEvent::Print(
+                        PrintLevel::from(level),
+                        parse_string(&mut cur),
+                    ));
+                }
+                MvdOps::StuffText => events.push(MvdEvent::StuffText(parse_string(&mut cur))),
+                _ => return Err(Q2ProtoError::UnknownOp(cmd_byte)),
+            }
+        }
+
+        Ok(events)
+    }
+
+    // serverdata, all the configstrings and a baseline frame, all in one go.
+    fn parse_serverdata(
+        &mut self,
+        cur: &mut Cursor<&[u8]>,
+        extra_bits: u8,
+        events: &mut Vec<MvdEvent>,
+    ) -> Option<Result<(), Q2ProtoError>> {
+        let protocol = cur.read_i32::<LittleEndian>().ok()?;
+        let minor_version = cur.read_u16::<LittleEndian>().ok()?;
+        if protocol != PROTOCOL_VERSION_MVD
+            || !(PROTOCOL_VERSION_MVD_MINIMUM..=PROTOCOL_VERSION_MVD_DEFAULT)
+                .contains(&minor_version)
+        {
+            return Some(Err(Q2ProtoError::UnsupportedMvdVersion(
+                protocol,
+                minor_version,
+            )));
+        }
+
+        let serverdata = MvdServerData {
+            protocol,
+            minor_version,
+            server_count: cur.read_i32::<LittleEndian>().ok()?,
+            gamedir: String::from_utf8_lossy(&parse_string(cur)).into_owned(),
+            client_num: cur.read_i16::<LittleEndian>().ok()?,
+            flags: extra_bits,
+        };
+
+        // a new level, nothing from the old one carries over.
+        self.serverdata = Some(serverdata.clone());
+        self.configstrings.clear();
+        self.players.clear();
+        self.entities.clear();
+        events.push(MvdEvent::ServerData(serverdata));
+
+        loop {
+            let index = cur.read_u16::<LittleEndian>().ok()?;
+            if index >= MAX_CONFIGSTRINGS {
+                break;
+            }
+
+            let value = parse_string(cur);
+            self.configstrings.insert(index, value.clone());
+            events.push(MvdEvent::ConfigString(index, value));
+        }
+
+        let frame = self.parse_frame(cur)?;
+        events.push(MvdEvent::Frame(Box::new(frame)));
+
+        Some(Ok(()))
+    }
+
+    // portal bits, then players until CLIENTNUM_NONE, then entities until number 0.
+    fn parse_frame(&mut self, cur: &mut Cursor<&[u8]>) -> Option<MvdFrame> {
+        let portal_len = cur.read_u8().ok()?;
+        let portal_bits = read_data(cur, portal_len as usize)?;
+
+        let mut players = Vec::new();
+        loop {
+            let number = cur.read_u8().ok()?;
+            if number == CLIENTNUM_NONE {
+                break;
+            }
+
+            let bits = cur.read_u16::<LittleEndian>().ok()?;
+            let mut state = self.players.remove(&number).unwrap_or_default();
+            parse_packet_player_state(cur, bits, &mut state)?;
+
+            let in_use = !has(bits, PacketPlayerStateBits::REMOVE);
+            if in_use {
+                self.players.insert(number, state.clone());
+            }
+            players.push(MvdPlayer {
+                number,
+                state,
+                in_use,
+            });
+        }
+
+        // events only last the one frame, like cl_deltaentity does it.
+        for ent in self.entities.values_mut() {
+            ent.event = 0;
+        }
+
+        let mut entities = Vec::new();
+        loop {
+            let (number, bits) = parse_entity_bits(cur)?;
+            if number == 0 {
+                break;
+            }
+
+            let delta = parse_delta_entity(number, bits, cur)?;
+            let mut ent = self.entities.remove(&number).unwrap_or_default();
+            ent.number = number;
+            ent.apply(&delta);
+
+            if !ent.remove {
+                self.entities.insert(number, ent.clone());
+            }
+            entities.push(ent);
+        }
+
+        Some(MvdFrame {
+            portal_bits,
+            players,
+            entities,
+        })
+    }
+}
+
+// the unicast length has its top bits in the op's extra bits.
+fn read_length(cur: &mut Cursor<&[u8]>, extra_bits: u8) -> Option<usize> {
+    Some(cur.read_u8().ok()? as usize | (extra_bits as usize) << 8)
+}
+
+fn read_data(cur: &mut Cursor<&[u8]>, len: usize) -> Option<Vec<u8>> {
+    let mut data = vec![0u8; len];
+    cur.read_exact(&mut data).ok()?;
+    Some(data)
+}
+
+// the server messages inside a unicast or multicast. one that doesn't parse only costs us
+// the rest of that message, not the whole block.
+fn parse_embedded(data: &[u8]) -> Vec<ClientEvent> {
+    let mut cur = Cursor::new(data);
+    let mut events = Vec::new();
+
+    while let Ok(cmd_byte) = cur.read_u8() {
+        match parse_op(cmd_byte, &mut cur) {
+            Ok(Some(evt)) => events.push(evt),
+            Ok(None) => {}
+            Err(_) => break,
+        }
+    }
+
+    events
+}
+
+// like svc_sound, but always with an entity and never with a position.
+fn parse_mvd_sound(cur: &mut Cursor<&[u8]>) -> Option<SoundMessage> {
+    let flags = cur.read_u8().ok()?;
+    let mut sound = SoundMessage {
+        sound_index: cur.read_u8().ok()?,
+        ..Default::default()
+    };
+
+    if flags & SND_VOLUME != 0 {
+        sound.volume = Some(cur.read_u8().ok()?);
+    }
+    if flags & SND_ATTENUATION != 0 {
+        sound.attenuation = Some(cur.read_u8().ok()?);
+    }
+    if flags & SND_OFFSET != 0 {
+        sound.time_offset = Some(cur.read_u8().ok()?);
+    }
+
+    let ent_channel = cur.read_u16::<LittleEndian>().ok()?;
+    sound.entity = Some((ent_channel >> 3) as i16);
+    sound.channel = Some((ent_channel & 7) as u8);
+
+    Some(sound)
+}
+
+// msg_parsedeltaplayerstate_packet. some vectors come in pieces, so this updates in place.
+fn parse_packet_player_state(
+    cur: &mut Cursor<&[u8]>,
+    bits: u16,
+    ps: &mut PlayerState,
+) -> Option<()> {
+    if has(bits, PacketPlayerStateBits::MTYPE) {
+        ps.pm_type = Some(cur.read_u8().ok()?);
+    }
+    if has(bits, PacketPlayerStateBits::MORIGIN) {
+        let mut origin = ps.origin.unwrap_or_default();
+        origin[0] = parse_coord(cur)?;
+        origin[1] = parse_coord(cur)?;
+        ps.origin = Some(origin);
+    }
+    if has(bits, PacketPlayerStateBits::MORIGIN2) {
+        let mut origin = ps.origin.unwrap_or_default();
+        origin[2] = parse_coord(cur)?;
+        ps.origin = Some(origin);
+    }
+    if has(bits, PacketPlayerStateBits::VIEWOFFSET) {
+        ps.view_offset = Some(parse_char_vec(cur, 0.25)?);
+    }
+    if has(bits, PacketPlayerStateBits::VIEWANGLES) {
+        let mut angles = ps.view_angles.unwrap_or_default();
+        angles[0] = parse_angle16(cur)?;
+        angles[1] = parse_angle16(cur)?;
+        ps.view_angles = Some(angles);
+    }
+    if has(bits, PacketPlayerStateBits::VIEWANGLE2) {
+        let mut angles = ps.view_angles.unwrap_or_default();
+        angles[2] = parse_angle16(cur)?;
+        ps.view_angles = Some(angles);
+    }
+    if has(bits, PacketPlayerStateBits::KICKANGLES) {
+        ps.kick_angles = Some(parse_char_vec(cur, 0.25)?);
+    }
+    if has(bits, PacketPlayerStateBits::WEAPONINDEX) {
+        ps.gun_index = Some(cur.read_u8().ok()?);
+    }
+    if has(bits, PacketPlayerStateBits::WEAPONFRAME) {
+        ps.gun_frame = Some(cur.read_u8().ok()?);
+    }
+    if has(bits, PacketPlayerStateBits::GUNOFFSET) {
+        ps.gun_offset = Some(parse_char_vec(cur, 0.25)?);
+    }
+    if has(bits, PacketPlayerStateBits::GUNANGLES) {
+        ps.gun_angles = Some(parse_char_vec(cur, 0.25)?);
+    }
+    if has(bits, PacketPlayerStateBits::BLEND) {
+        ps.blend = Some([
+            cur.read_u8().ok()? as f32 / 255.0,
+            cur.read_u8().ok()? as f32 / 255.0,
+            cur.read_u8().ok()? as f32 / 255.0,
+            cur.read_u8().ok()? as f32 / 255.0,
+        ]);
+    }
+    if has(bits, PacketPlayerStateBits::FOV) {
+        ps.fov = Some(cur.read_u8().ok()?);
+    }
+    if has(bits, PacketPlayerStateBits::RDFLAGS) {
+        ps.rdflags = Some(cur.read_u8().ok()?);
+    }
+    if has(bits, PacketPlayerStateBits::STATS) {
+        let stat_bits = cur.read_u32::<LittleEndian>().ok()?;
+        for (i, stat) in ps.stats.iter_mut().enumerate() {
+            if stat_bits & (1 << i) != 0 {
+                *stat = Some(cur.read_i16::<LittleEndian>().ok()?);
+            }
+        }
+    }
+
+    Some(())
+}
+
+// reads a .mvd2: the magic, then messages with a 16 bit length in front. a zero length ends it.
+pub struct MvdReader<R: Read> {
+    reader: R,
+    state: MvdState,
+    pending: VecDeque<MvdEvent>,
+    done: bool,
+}
+
+impl MvdReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Q2ProtoError> {
+        MvdReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> MvdReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, Q2ProtoError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).map_err(Q2ProtoError::Io)?;
+        if &magic != MVD_MAGIC {
+            return Err(Q2ProtoError::MalformedResponse(
+                String::from_utf8_lossy(&magic).into_owned(),
+            ));
+        }
+
+        Ok(MvdReader {
+            reader,
+            state: MvdState::new(),
+            pending: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    // where the demo is at, for views and the like.
+    pub fn state(&self) -> &MvdState {
+        &self.state
+    }
+
+    // the next message as it is in the file. None once the demo is over.
+    pub fn next_block(&mut self) -> Result<Option<Vec<u8>>, Q2ProtoError> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let len = match self.reader.read_u16::<LittleEndian>() {
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(Q2ProtoError::Io(e)),
+        };
+        if len == 0 {
+            self.done = true;
+            return Ok(None);
+        }
+
+        let mut block = vec![0u8; len as usize];
+        self.reader
+            .read_exact(&mut block)
+            .map_err(Q2ProtoError::Io)?;
+
+        Ok(Some(block))
+    }
+
+    // all the events in the next message. None once the demo is over.
+    pub fn next_events(&mut self) -> Result<Option<Vec<MvdEvent>>, Q2ProtoError> {
+        match self.next_block()? {
+            Some(block) => Ok(Some(self.state.parse_message(&block)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+// stops at the first error.
+impl<R: Read> Iterator for MvdReader<R> {
+    type Item = Result<MvdEvent, Q2ProtoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(evt) = self.pending.pop_front() {
+                return Some(Ok(evt));
+            }
+
+            match self.next_events() {
+                Ok(Some(events)) => self.pending.extend(events),
+                Ok(None) => return None,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::objects::{write_delta_entity, write_string};
+    use crate::ServerToClientOps;
+
+    // serverdata with `player` in the game and entity 5 in the baseline frame.
+    pub(crate) fn serverdata(player: u8) -> Vec<u8> {
+        let mut msg = vec![MvdOps::ServerData as u8];
+        msg.extend(PROTOCOL_VERSION_MVD.to_le_bytes());
+        msg.extend(PROTOCOL_VERSION_MVD_DEFAULT.to_le_bytes());
+        msg.extend(1i32.to_le_bytes());
+        write_string(&mut msg, b"baseq2");
+        msg.extend(0i16.to_le_bytes());
+        msg.extend((CS_PLAYERSKINS + player as u16).to_le_bytes());
+        write_string(&mut msg, b"bob\\male/grunt");
+        msg.extend(MAX_CONFIGSTRINGS.to_le_bytes());
+
+        let ent = DeltaEntity {
+            number: 5,
+            model_index: Some(1),
+            ..Default::default()
+        };
+        msg.extend(&frame(&[player], &[ent])[1..]);
+        msg
+    }
+
+    // a frame where the players don't move. the entities are deltas.
+    pub(crate) fn frame(players: &[u8], entities: &[DeltaEntity]) -> Vec<u8> {
+        let mut msg = vec![MvdOps::Frame as u8, 0];
+        for player in players {
+            msg.push(*player);
+            msg.extend(0u16.to_le_bytes());
+        }
+        msg.push(CLIENTNUM_NONE);
+        for ent in entities {
+            write_delta_entity(&mut msg, ent);
+        }
+        msg.extend([0, 0]);
+        msg
+    }
+
+    // an entity event, footsteps and the like.
+    pub(crate) fn event(number: i16) -> DeltaEntity {
+        DeltaEntity {
+            number,
+            event: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn events_last_one_frame() {
+        let mut state = MvdState::new();
+        state.parse_message(&serverdata(2)).unwrap();
+
+        state.parse_message(&frame(&[], &[event(5)])).unwrap();
+        assert_eq!(state.entities()[&5].event, 2);
+
+        state.parse_message(&frame(&[], &[])).unwrap();
+        assert_eq!(state.entities()[&5].event, 0);
+        assert_eq!(state.entities()[&5].model_index, Some(1));
+    }
+
+    #[test]
+    fn bad_multicast_keeps_the_block() {
+        let mut state = MvdState::new();
+
+        // svc_print, then something that isn't an op.
+        let mut inner = vec![ServerToClientOps::Print as u8, 2];
+        write_string(&mut inner, b"x died");
+        inner.push(200);
+
+        let mut msg = vec![MvdOps::MulticastAll as u8, inner.len() as u8];
+        msg.extend(&inner);
+        msg.push(MvdOps::Print as u8);
+        msg.push(2);
+        write_string(&mut msg, b"after");
+
+        let events = state.parse_message(&msg).unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [MvdEvent::Multicast { events, .. }, MvdEvent::Print(_, text)]
+                if events.len() == 1 && text == b"after"
+        ));
+    }
+}
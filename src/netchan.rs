@@ -1,13 +1,81 @@
+use super::user_info::UserInfo;
 use super::MsgBuf;
 use super::MAX_WRITEABLE_SIZE;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Cursor, Seek, Write};
+use std::time::{Duration, Instant};
+
+// what a stock client ships with when the userinfo doesn't say otherwise.
+pub const DEFAULT_MAXFPS: u32 = 90;
 
 pub trait NetChan {
     // If it returns true, the packet should be used.
     fn process<T: AsRef<[u8]>>(&mut self, cur: &mut Cursor<T>) -> bool;
-    fn transmit(&mut self, data: &[u8]) -> Cursor<[u8; MAX_WRITEABLE_SIZE]>;
+    fn transmit(&mut self, data: &[u8], now: Instant) -> Cursor<[u8; MAX_WRITEABLE_SIZE]>;
     fn should_transmit(&self) -> bool;
+    // Netchan_CanReliable: false while a reliable payload is still waiting for its ack.
+    fn can_reliable(&self) -> bool;
+    // Netchan_CanPacket: false while we're choked by the rate limit.
+    fn can_packet(&self, now: Instant) -> bool;
+    // the earliest time can_packet will say yes. None if it already does regardless of time.
+    fn next_packet_time(&self) -> Option<Instant>;
+}
+
+// Limits on what we send. A zero means "no limit". The userinfo `rate` isn't one of them:
+// that's for the server, it caps what it sends us. What a real client does limit is how
+// often it sends, once per frame at `cl_maxfps`, so that's what `packets_per_second` is.
+// `bytes_per_second` additionally caps our upload, which stock clients don't.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes_per_second: u32,
+    pub packets_per_second: u32,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_second: u32, packets_per_second: u32) -> RateLimit {
+        RateLimit {
+            bytes_per_second,
+            packets_per_second,
+        }
+    }
+
+    pub fn unlimited() -> RateLimit {
+        RateLimit::new(0, 0)
+    }
+
+    // the packet cadence of a real client with this userinfo's `cl_maxfps`. A missing or
+    // garbage value falls back to the stock default.
+    pub fn from_userinfo(userinfo: &UserInfo) -> RateLimit {
+        let maxfps = userinfo
+            .keys
+            .get("cl_maxfps")
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .unwrap_or(DEFAULT_MAXFPS);
+
+        RateLimit::new(0, maxfps)
+    }
+
+    fn packet_interval(&self) -> Option<Duration> {
+        if self.packets_per_second == 0 {
+            return None;
+        }
+
+        Some(Duration::from_secs(1) / self.packets_per_second)
+    }
+
+    fn transfer_time(&self, bytes: usize) -> Duration {
+        if self.bytes_per_second == 0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64)
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit::new(0, DEFAULT_MAXFPS)
+    }
 }
 
 pub struct NetChanVanilla {
@@ -29,6 +97,10 @@ pub struct NetChanVanilla {
     qport: u16,
 
     reliable_buf: Cursor<[u8; MAX_WRITEABLE_SIZE]>,
+
+    rate: RateLimit,
+    cleartime: Option<Instant>, // when the bytes we've sent so far are "paid off"
+    last_sent: Option<Instant>,
 }
 
 impl NetChanVanilla {
@@ -47,8 +119,19 @@ impl NetChanVanilla {
             qport,
             is_reliable_ack_pending: false,
             reliable_buf: Cursor::new([0; MAX_WRITEABLE_SIZE]),
+            rate: RateLimit::default(),
+            cleartime: None,
+            last_sent: None,
         }
     }
+
+    pub fn set_rate_limit(&mut self, rate: RateLimit) {
+        self.rate = rate;
+    }
+
+    pub fn rate_limit(&self) -> RateLimit {
+        self.rate
+    }
 }
 
 // old q2/r1q2 netchan
@@ -97,7 +180,7 @@ impl NetChan for NetChanVanilla {
         true
     }
 
-    fn transmit(&mut self, data: &[u8]) -> Cursor<[u8; MAX_WRITEABLE_SIZE]> {
+    fn transmit(&mut self, data: &[u8], now: Instant) -> Cursor<[u8; MAX_WRITEABLE_SIZE]> {
         let mut should_send_reliable = false;
         if self.incoming_acknowledged > self.last_sent_reliable_sequence
             && self.incoming_reliable_acknowledged != self.reliable_sequence
@@ -109,7 +192,7 @@ impl NetChan for NetChanVanilla {
          * and then advance the reliable sequence so let know there's a reliable payload
          * in this case, we should send a reliable payload.
          */
-        if self.message.cur.position() > 0 && self.reliable_buf.position() == 0 {
+        if self.message.cur.position() > 0 && self.can_reliable() {
             // this is fine since both buffers have the same size, so just unwrap.
             let lim = self.message.cur.position() as usize;
             let msg_slice = self.message.cur.get_ref().as_slice();
@@ -162,6 +245,15 @@ impl NetChan for NetChanVanilla {
         self.outgoing_sequence += 1;
         self.is_reliable_ack_pending = false;
 
+        // like qw's Netchan_Transmit: push cleartime forward by however long the rate
+        // says this packet takes to go out.
+        let base = match self.cleartime {
+            Some(clear) if clear > now => clear,
+            _ => now,
+        };
+        self.cleartime = Some(base + self.rate.transfer_time(packet.position() as usize));
+        self.last_sent = Some(now);
+
         packet
     }
 
@@ -170,4 +262,81 @@ impl NetChan for NetChanVanilla {
             || self.message.cur.position() > 0
             || self.reliable_buf.position() > 0
     }
+
+    fn can_reliable(&self) -> bool {
+        self.reliable_buf.position() == 0
+    }
+
+    fn can_packet(&self, now: Instant) -> bool {
+        if let (Some(interval), Some(last)) = (self.rate.packet_interval(), self.last_sent) {
+            if now < last + interval {
+                return false;
+            }
+        }
+
+        match self.cleartime {
+            Some(clear) => clear <= now,
+            None => true,
+        }
+    }
+
+    fn next_packet_time(&self) -> Option<Instant> {
+        let interval_end = match (self.rate.packet_interval(), self.last_sent) {
+            (Some(interval), Some(last)) => Some(last + interval),
+            _ => None,
+        };
+
+        match (interval_end, self.cleartime) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cadence_from_userinfo() {
+        let rate = RateLimit::from_userinfo(&UserInfo::from_string("\\cl_maxfps\\30\\rate\\8000"));
+        // the rate is the server's business.
+        assert_eq!(rate, RateLimit::new(0, 30));
+
+        let rate = RateLimit::from_userinfo(&UserInfo::from_string("\\cl_maxfps\\lots"));
+        assert_eq!(rate, RateLimit::new(0, DEFAULT_MAXFPS));
+    }
+
+    #[test]
+    fn one_packet_per_frame() {
+        let mut chan = NetChanVanilla::new(true, 0);
+        chan.set_rate_limit(RateLimit::new(0, 10));
+        let now = Instant::now();
+        let frame = Duration::from_millis(100);
+
+        assert!(chan.can_packet(now));
+        assert_eq!(chan.next_packet_time(), None);
+
+        chan.transmit(&[0u8; 1000], now);
+        assert!(!chan.can_packet(now + frame / 2));
+        assert_eq!(chan.next_packet_time(), Some(now + frame));
+        assert!(chan.can_packet(now + frame));
+    }
+
+    #[test]
+    fn bytes_per_second_caps_upload() {
+        let mut chan = NetChanVanilla::new(true, 0);
+        chan.set_rate_limit(RateLimit::new(1000, 0));
+        let now = Instant::now();
+
+        // 10 bytes of header, so half a second at 1000 bytes a second.
+        chan.transmit(&[0u8; 490], now);
+        assert!(!chan.can_packet(now + Duration::from_millis(400)));
+        assert!(chan.can_packet(now + Duration::from_millis(500)));
+
+        let mut chan = NetChanVanilla::new(true, 0);
+        chan.set_rate_limit(RateLimit::unlimited());
+        chan.transmit(&[0u8; 490], now);
+        assert!(chan.can_packet(now));
+    }
 }
@@ -0,0 +1,402 @@
+use super::text::decode;
+
+// the MOD_ values, from the stock game, ctf, and the two mission packs. the ones that share
+// a message with another one aren't here, there's no telling them apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MeansOfDeath {
+    Unknown,
+    Blaster,
+    Shotgun,
+    SuperShotgun,
+    Machinegun,
+    Chaingun,
+    Grenade,
+    GrenadeSplash,
+    Rocket,
+    RocketSplash,
+    Hyperblaster,
+    Railgun,
+    BfgLaser,
+    BfgBlast,
+    BfgEffect,
+    HandGrenade,
+    HandGrenadeSplash,
+    Water,
+    Slime,
+    Lava,
+    Crush,
+    Telefrag,
+    Falling,
+    Suicide,
+    HeldGrenade,
+    Explosive,
+    ExitLevel,
+    TargetLaser,
+    TriggerHurt,
+    TargetBlaster,
+    // ctf
+    Grapple,
+    // the reckoning
+    Ripper,
+    Phalanx,
+    Trap,
+    // ground zero
+    Chainfist,
+    Disintegrator,
+    EtfRifle,
+    Heatbeam,
+    Tesla,
+    ProxMine,
+    Nuke,
+    VengeanceSphere,
+    HunterSphere,
+    DefenderSphere,
+    Tracker,
+    DoppleExplode,
+    DoppleVengeance,
+    DoppleHunter,
+}
+
+// someone died. `killer` is only set when somebody else gets the frag for it, so
+// suicides and the world leave it empty.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Kill {
+    pub victim: String,
+    pub killer: Option<String>,
+    pub means_of_death: MeansOfDeath,
+}
+
+impl Kill {
+    // the victim loses a frag for these.
+    pub fn is_suicide(&self) -> bool {
+        self.killer.is_none()
+    }
+}
+
+// "<victim> <message> <killer><message2>", as clientobituary prints it.
+const KILL_MESSAGES: &[(MeansOfDeath, &str, &str)] = &[
+    (MeansOfDeath::Blaster, "was blasted by", ""),
+    (MeansOfDeath::Shotgun, "was gunned down by", ""),
+    (
+        MeansOfDeath::SuperShotgun,
+        "was blown away by",
+        "'s super shotgun",
+    ),
+    (MeansOfDeath::Machinegun, "was machinegunned by", ""),
+    (MeansOfDeath::Chaingun, "was cut in half by", "'s chaingun"),
+    (MeansOfDeath::Grenade, "was popped by", "'s grenade"),
+    (
+        MeansOfDeath::GrenadeSplash,
+        "was shredded by",
+        "'s shrapnel",
+    ),
+    (MeansOfDeath::Rocket, "ate", "'s rocket"),
+    (MeansOfDeath::RocketSplash, "almost dodged", "'s rocket"),
+    (
+        MeansOfDeath::Hyperblaster,
+        "was melted by",
+        "'s hyperblaster",
+    ),
+    (MeansOfDeath::Railgun, "was railed by", ""),
+    (
+        MeansOfDeath::BfgLaser,
+        "saw the pretty lights from",
+        "'s BFG",
+    ),
+    (
+        MeansOfDeath::BfgBlast,
+        "was disintegrated by",
+        "'s BFG blast",
+    ),
+    (MeansOfDeath::BfgEffect, "couldn't hide from", "'s BFG"),
+    (MeansOfDeath::HandGrenade, "caught", "'s handgrenade"),
+    (
+        MeansOfDeath::HandGrenadeSplash,
+        "didn't see",
+        "'s handgrenade",
+    ),
+    (MeansOfDeath::HeldGrenade, "feels", "'s pain"),
+    (
+        MeansOfDeath::Telefrag,
+        "tried to invade",
+        "'s personal space",
+    ),
+    (MeansOfDeath::Grapple, "was caught by", "'s grapple"),
+    (MeansOfDeath::Ripper, "ripped to shreds by", "'s ripper gun"),
+    (MeansOfDeath::Phalanx, "was evaporated by", ""),
+    (MeansOfDeath::Trap, "caught in trap by", ""),
+    (MeansOfDeath::Chainfist, "was shredded by", "'s ripsaw"),
+    (
+        MeansOfDeath::Disintegrator,
+        "lost {his} grip courtesy of",
+        "'s disintegrator",
+    ),
+    (MeansOfDeath::EtfRifle, "was perforated by", ""),
+    (MeansOfDeath::Heatbeam, "was scorched by", "'s plasma beam"),
+    (MeansOfDeath::Tesla, "was enlightened by", "'s tesla mine"),
+    (
+        MeansOfDeath::ProxMine,
+        "got too close to",
+        "'s proximity mine",
+    ),
+    (MeansOfDeath::Nuke, "was nuked by", "'s antimatter bomb"),
+    (
+        MeansOfDeath::VengeanceSphere,
+        "was purged by",
+        "'s vengeance sphere",
+    ),
+    (
+        MeansOfDeath::DefenderSphere,
+        "had a blast with",
+        "'s defender sphere",
+    ),
+    (
+        MeansOfDeath::HunterSphere,
+        "was killed like a dog by",
+        "'s hunter sphere",
+    ),
+    (MeansOfDeath::Tracker, "was annihilated by", "'s disruptor"),
+    (
+        MeansOfDeath::DoppleExplode,
+        "was blown up by",
+        "'s doppleganger",
+    ),
+    (
+        MeansOfDeath::DoppleVengeance,
+        "was purged by",
+        "'s doppleganger",
+    ),
+    (
+        MeansOfDeath::DoppleHunter,
+        "was hunted down by",
+        "'s doppleganger",
+    ),
+];
+
+// "<victim> <message>": the world, or their own fault.
+const DEATH_MESSAGES: &[(MeansOfDeath, &str)] = &[
+    (MeansOfDeath::Suicide, "suicides."),
+    (MeansOfDeath::Falling, "cratered."),
+    (MeansOfDeath::Crush, "was squished."),
+    (MeansOfDeath::Water, "sank like a rock."),
+    (MeansOfDeath::Slime, "melted."),
+    (MeansOfDeath::Lava, "does a back flip into the lava."),
+    (MeansOfDeath::Explosive, "blew up."),
+    (MeansOfDeath::ExitLevel, "found a way out."),
+    (MeansOfDeath::TargetLaser, "saw the light."),
+    (MeansOfDeath::TargetBlaster, "got blasted."),
+    (MeansOfDeath::TriggerHurt, "was in the wrong place."),
+    (MeansOfDeath::HeldGrenade, "tried to put the pin back in."),
+    (
+        MeansOfDeath::HandGrenadeSplash,
+        "tripped on {his} own grenade.",
+    ),
+    (MeansOfDeath::RocketSplash, "blew {him}self up."),
+    (MeansOfDeath::BfgBlast, "should have used a smaller gun."),
+    (MeansOfDeath::Trap, "was sucked into {his} own trap."),
+    (MeansOfDeath::Unknown, "killed {him}self."),
+    (MeansOfDeath::Unknown, "died."),
+];
+
+// the game picks the pronoun from the player's skin. the mission packs added "it".
+fn pronouns(message: &str) -> Vec<String> {
+    if message.contains("{his}") {
+        ["his", "her", "its"]
+            .iter()
+            .map(|p| message.replace("{his}", p))
+            .collect()
+    } else if message.contains("{him}") {
+        ["him", "her", "it"]
+            .iter()
+            .map(|p| message.replace("{him}", p))
+            .collect()
+    } else {
+        vec![message.to_string()]
+    }
+}
+
+// a death message, as it comes in a PrintLevel::MEDIUM print. names can have anything in
+// them, including the messages, so when `players` isn't empty the reading where everyone
+// is a known player wins.
+pub fn parse_obituary(text: &[u8], players: &[String]) -> Option<Kill> {
+    let text = decode(text);
+    let text = text.trim_end_matches('\n');
+    let known = |name: &str| players.is_empty() || players.iter().any(|p| p == name);
+
+    let mut fallback = None;
+    let mut consider = |kill: Kill| {
+        let all_known = known(&kill.victim) && kill.killer.as_deref().is_none_or(known);
+        if all_known {
+            return Some(kill);
+        }
+        fallback.get_or_insert(kill);
+        None
+    };
+
+    for (means_of_death, message, message2) in KILL_MESSAGES {
+        for message in pronouns(message) {
+            let separator = format!(" {} ", message);
+            // not match_indices: with "I ate" in the name, " ate " turns up overlapping itself.
+            let starts = text
+                .char_indices()
+                .map(|(at, _)| at)
+                .filter(|at| text[*at..].starts_with(&separator));
+            for at in starts {
+                let victim = &text[..at];
+                let Some(killer) = text[at + separator.len()..].strip_suffix(message2) else {
+                    continue;
+                };
+
+                let kill = Kill {
+                    victim: victim.to_string(),
+                    killer: Some(killer.to_string()),
+                    means_of_death: *means_of_death,
+                };
+                if let Some(kill) = consider(kill) {
+                    return Some(kill);
+                }
+            }
+        }
+    }
+
+    for (means_of_death, message) in DEATH_MESSAGES {
+        for message in pronouns(message) {
+            let Some(victim) = text
+                .strip_suffix(message.as_str())
+                .and_then(|v| v.strip_suffix(' '))
+            else {
+                continue;
+            };
+
+            let kill = Kill {
+                victim: victim.to_string(),
+                killer: None,
+                means_of_death: *means_of_death,
+            };
+            if let Some(kill) = consider(kill) {
+                return Some(kill);
+            }
+        }
+    }
+
+    fallback
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn players(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn kill(victim: &str, killer: Option<&str>, means_of_death: MeansOfDeath) -> Option<Kill> {
+        Some(Kill {
+            victim: victim.to_string(),
+            killer: killer.map(str::to_string),
+            means_of_death,
+        })
+    }
+
+    #[test]
+    fn kills() {
+        let players = players(&["Alice", "Bob"]);
+
+        let railed = parse_obituary(b"Alice was railed by Bob\n", &players);
+        assert_eq!(railed, kill("Alice", Some("Bob"), MeansOfDeath::Railgun));
+        assert!(!railed.unwrap().is_suicide());
+
+        assert_eq!(
+            parse_obituary(b"Bob ate Alice's rocket\n", &players),
+            kill("Bob", Some("Alice"), MeansOfDeath::Rocket)
+        );
+        // without the players, whatever reads as a kill will do.
+        assert_eq!(
+            parse_obituary(b"Bob almost dodged Alice's rocket\n", &[]),
+            kill("Bob", Some("Alice"), MeansOfDeath::RocketSplash)
+        );
+    }
+
+    #[test]
+    fn suicides() {
+        let players = players(&["Alice"]);
+
+        let suicide = parse_obituary(b"Alice suicides.\n", &players);
+        assert_eq!(suicide, kill("Alice", None, MeansOfDeath::Suicide));
+        assert!(suicide.unwrap().is_suicide());
+
+        assert_eq!(
+            parse_obituary(b"Alice tried to put the pin back in.\n", &players),
+            kill("Alice", None, MeansOfDeath::HeldGrenade)
+        );
+    }
+
+    #[test]
+    fn world_deaths() {
+        let players = players(&["Bob"]);
+
+        assert_eq!(
+            parse_obituary(b"Bob cratered.\n", &players),
+            kill("Bob", None, MeansOfDeath::Falling)
+        );
+        assert_eq!(
+            parse_obituary(b"Bob does a back flip into the lava.\n", &players),
+            kill("Bob", None, MeansOfDeath::Lava)
+        );
+        assert_eq!(parse_obituary(b"Bob entered the game\n", &players), None);
+    }
+
+    #[test]
+    fn pronouns() {
+        for pronoun in ["his", "her", "its"] {
+            let text = format!("Gekk tripped on {} own grenade.\n", pronoun);
+            assert_eq!(
+                parse_obituary(text.as_bytes(), &[]),
+                kill("Gekk", None, MeansOfDeath::HandGrenadeSplash)
+            );
+
+            let text = format!(
+                "Alice lost {} grip courtesy of Bob's disintegrator\n",
+                pronoun
+            );
+            assert_eq!(
+                parse_obituary(text.as_bytes(), &[]),
+                kill("Alice", Some("Bob"), MeansOfDeath::Disintegrator)
+            );
+        }
+
+        for pronoun in ["him", "her", "it"] {
+            let text = format!("Gekk blew {}self up.\n", pronoun);
+            assert_eq!(
+                parse_obituary(text.as_bytes(), &[]),
+                kill("Gekk", None, MeansOfDeath::RocketSplash)
+            );
+        }
+    }
+
+    #[test]
+    fn names_with_messages_in_them() {
+        let players = players(&["I ate", "Bob was railed by", "Alice"]);
+
+        assert_eq!(
+            parse_obituary(b"I ate ate Alice's rocket\n", &players),
+            kill("I ate", Some("Alice"), MeansOfDeath::Rocket)
+        );
+        assert_eq!(
+            parse_obituary(b"Alice ate I ate's rocket\n", &players),
+            kill("Alice", Some("I ate"), MeansOfDeath::Rocket)
+        );
+        assert_eq!(
+            parse_obituary(b"Bob was railed by was railed by Alice\n", &players),
+            kill("Bob was railed by", Some("Alice"), MeansOfDeath::Railgun)
+        );
+        assert_eq!(
+            parse_obituary(b"Alice was railed by Bob was railed by\n", &players),
+            kill("Alice", Some("Bob was railed by"), MeansOfDeath::Railgun)
+        );
+        assert_eq!(
+            parse_obituary(b"I ate cratered.\n", &players),
+            kill("I ate", None, MeansOfDeath::Falling)
+        );
+    }
+}
@@ -1,7 +1,11 @@
+use super::error::Q2ProtoError;
+use super::text::decode;
 use super::ClientEvent;
 use super::ClientEvent::ServerData;
-use byteorder::{LittleEndian, ReadBytesExt};
-use std::io::{Cursor};
+use super::ServerToClientOps;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
 use std::ops::{BitAnd, BitOr};
 
 pub struct PackedEntity {}
@@ -68,7 +72,7 @@ impl BitOr<EntityStateBits> for EntityStateBits {
     }
 }
 
-#[derive(Eq, PartialEq, Hash)]
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug)]
 pub enum PrintLevel {
     LOW = 0,
     // pickup messages
@@ -93,29 +97,29 @@ impl From<u8> for PrintLevel {
     }
 }
 
-#[derive(Eq, Hash, PartialEq)]
+#[derive(Eq, Hash, PartialEq, Clone, Debug)]
 pub struct R1Q2ProtocolInfo;
 
-#[derive(Eq, Hash, PartialEq)]
+#[derive(Eq, Hash, PartialEq, Clone, Debug)]
 pub struct Q2ProProtocolInfo;
 
-#[derive(Eq, Hash, PartialEq)]
+#[derive(Eq, Hash, PartialEq, Clone, Debug)]
 pub enum ProtocolInfo {
     Vanilla,
     R1Q2(R1Q2ProtocolInfo),
     Q2Pro(Q2ProProtocolInfo),
 }
 
-#[derive(Eq, Hash, PartialEq)]
+#[derive(Eq, Hash, PartialEq, Clone, Debug)]
 pub struct ServerDataMessage {
-    protocol: u32,
-    srv_count: u32,
-    attract_loop: u8,
-    gamedir: String,
-    clnum: u16,
-    levelname: String,
+    pub protocol: u32,
+    pub srv_count: u32,
+    pub attract_loop: u8,
+    pub gamedir: String,
+    pub clnum: u16,
+    pub levelname: String,
     // protocol specific info below
-    protocol_info: ProtocolInfo,
+    pub protocol_info: ProtocolInfo,
 }
 
 pub fn parse_string<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Vec<u8> {
@@ -133,29 +137,39 @@ pub fn parse_string<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Vec<u8> {
     out
 }
 
-// may return characters not printable in the utf8 range, so...
-pub fn parse_print<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Option<ClientEvent> {
-    let level = PrintLevel::from(cur.read_u8().ok()?);
+// the text is in the q2 charset, not utf8. text::decode it for showing.
+pub fn parse_print<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Result<ClientEvent, Q2ProtoError> {
+    let level = PrintLevel::from(
+        cur.read_u8()
+            .map_err(|_| Q2ProtoError::Truncated(ServerToClientOps::Print))?,
+    );
     let content = parse_string(cur);
 
-    Some(ClientEvent::Print(level, content))
+    Ok(ClientEvent::Print(level, content))
 }
 
-pub fn parse_serverdata<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Option<ClientEvent> {
+pub fn parse_serverdata<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Result<ClientEvent, Q2ProtoError> {
+    read_serverdata(cur).ok_or(Q2ProtoError::Truncated(ServerToClientOps::ServerData))
+}
+
+fn read_serverdata<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Option<ClientEvent> {
     Some(ServerData(ServerDataMessage {
         protocol: cur.read_u32::<LittleEndian>().ok()?,
         srv_count: cur.read_u32::<LittleEndian>().ok()?,
         attract_loop: cur.read_u8().ok()?,
-        gamedir: String::from_utf8(parse_string(cur)).ok()?,
+        gamedir: String::from_utf8_lossy(&parse_string(cur)).into_owned(),
         clnum: cur.read_u16::<LittleEndian>().ok()?,
-        levelname: String::from_utf8(parse_string(cur)).ok()?,
+        levelname: String::from_utf8_lossy(&parse_string(cur)).into_owned(),
         protocol_info: ProtocolInfo::Vanilla,
     }))
 }
 
-pub fn parse_configstring<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Option<ClientEvent> {
-    Some(ClientEvent::ConfigString(
-        cur.read_u16::<LittleEndian>().ok()?,
+pub fn parse_configstring<T: AsRef<[u8]>>(
+    cur: &mut Cursor<T>,
+) -> Result<ClientEvent, Q2ProtoError> {
+    Ok(ClientEvent::ConfigString(
+        cur.read_u16::<LittleEndian>()
+            .map_err(|_| Q2ProtoError::Truncated(ServerToClientOps::ConfigString))?,
         parse_string(cur),
     ))
 }
@@ -176,50 +190,128 @@ pub fn parse_entity_bits<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Option<(i16, u3
     let number = if total & EntityStateBits::NUMBER16 != 0 {
         cur.read_i16::<LittleEndian>().ok()?
     } else {
-        cur.read_i8().ok()? as i16
+        cur.read_u8().ok()? as i16
     };
 
     Some((number, total))
 }
 
 // fields that are not None are fields that changed.
-#[allow(dead_code)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct DeltaEntity {
-    number: i16,
-    model_index: Option<u8>,
-    model_index2: Option<u8>,
-    model_index3: Option<u8>,
-    model_index4: Option<u8>,
-    frame: Option<i16>,
-    skin: Option<u32>,
-    effects: Option<u32>,
-    render_fx: Option<u32>,
-    origin0: Option<f32>,
-    origin1: Option<f32>,
-    origin2: Option<f32>,
-    angle0: Option<f32>,
-    angle1: Option<f32>,
-    angle2: Option<f32>,
-    old_origin0: Option<f32>,
-    old_origin1: Option<f32>,
-    old_origin2: Option<f32>,
+    pub number: i16,
+    pub model_index: Option<u8>,
+    pub model_index2: Option<u8>,
+    pub model_index3: Option<u8>,
+    pub model_index4: Option<u8>,
+    pub frame: Option<i16>,
+    pub skin: Option<u32>,
+    pub effects: Option<u32>,
+    pub render_fx: Option<u32>,
+    pub origin0: Option<f32>,
+    pub origin1: Option<f32>,
+    pub origin2: Option<f32>,
+    pub angle0: Option<f32>,
+    pub angle1: Option<f32>,
+    pub angle2: Option<f32>,
+    pub old_origin0: Option<f32>,
+    pub old_origin1: Option<f32>,
+    pub old_origin2: Option<f32>,
     // these are i32 in the q2 source, but only a byte is ever parsed out of a packet
-    sound: Option<u8>,
-    event: u8,
-    solid: Option<u32>,
+    pub sound: Option<u8>,
+    pub event: u8,
+    pub solid: Option<u32>,
+    // the entity left the frame.
+    pub remove: bool,
+}
+
+impl DeltaEntity {
+    pub fn number(&self) -> i16 {
+        self.number
+    }
+
+    // bring this up to date with a delta for the same entity.
+    pub fn apply(&mut self, delta: &DeltaEntity) {
+        fn take<T: Copy>(field: &mut Option<T>, delta: Option<T>) {
+            if delta.is_some() {
+                *field = delta;
+            }
+        }
+
+        take(&mut self.model_index, delta.model_index);
+        take(&mut self.model_index2, delta.model_index2);
+        take(&mut self.model_index3, delta.model_index3);
+        take(&mut self.model_index4, delta.model_index4);
+        take(&mut self.frame, delta.frame);
+        take(&mut self.skin, delta.skin);
+        take(&mut self.effects, delta.effects);
+        take(&mut self.render_fx, delta.render_fx);
+        take(&mut self.origin0, delta.origin0);
+        take(&mut self.origin1, delta.origin1);
+        take(&mut self.origin2, delta.origin2);
+        take(&mut self.angle0, delta.angle0);
+        take(&mut self.angle1, delta.angle1);
+        take(&mut self.angle2, delta.angle2);
+        take(&mut self.old_origin0, delta.old_origin0);
+        take(&mut self.old_origin1, delta.old_origin1);
+        take(&mut self.old_origin2, delta.old_origin2);
+        take(&mut self.sound, delta.sound);
+        take(&mut self.solid, delta.solid);
+        // events only last the one frame.
+        self.event = delta.event;
+        self.remove = delta.remove;
+    }
+
+    // the other way around: what has to be sent to get from `from` to this.
+    pub fn delta_from(&self, from: &DeltaEntity) -> DeltaEntity {
+        fn diff<T: Copy + PartialEq>(field: Option<T>, from: Option<T>) -> Option<T> {
+            if field != from {
+                field
+            } else {
+                None
+            }
+        }
+
+        DeltaEntity {
+            number: self.number,
+            model_index: diff(self.model_index, from.model_index),
+            model_index2: diff(self.model_index2, from.model_index2),
+            model_index3: diff(self.model_index3, from.model_index3),
+            model_index4: diff(self.model_index4, from.model_index4),
+            frame: diff(self.frame, from.frame),
+            skin: diff(self.skin, from.skin),
+            effects: diff(self.effects, from.effects),
+            render_fx: diff(self.render_fx, from.render_fx),
+            origin0: diff(self.origin0, from.origin0),
+            origin1: diff(self.origin1, from.origin1),
+            origin2: diff(self.origin2, from.origin2),
+            angle0: diff(self.angle0, from.angle0),
+            angle1: diff(self.angle1, from.angle1),
+            angle2: diff(self.angle2, from.angle2),
+            old_origin0: diff(self.old_origin0, from.old_origin0),
+            old_origin1: diff(self.old_origin1, from.old_origin1),
+            old_origin2: diff(self.old_origin2, from.old_origin2),
+            sound: diff(self.sound, from.sound),
+            event: self.event,
+            solid: diff(self.solid, from.solid),
+            remove: self.remove,
+        }
+    }
 }
 
-pub fn parse_baseline<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Option<ClientEvent> {
-    let (number, bits) = parse_entity_bits(cur)?;
-    parse_delta_entity(number, bits, cur)
+pub fn parse_baseline<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Result<ClientEvent, Q2ProtoError> {
+    parse_entity_bits(cur)
+        .and_then(|(number, bits)| parse_delta_entity(number, bits, cur))
+        .map(ClientEvent::DeltaEntity)
+        .ok_or(Q2ProtoError::Truncated(ServerToClientOps::SpawnBaseline))
 }
 
-fn parse_delta_entity<T: AsRef<[u8]>>(
+pub(crate) fn parse_delta_entity<T: AsRef<[u8]>>(
     entnum: i16,
     bits: u32,
     cur: &mut Cursor<T>,
-) -> Option<ClientEvent> {
-    Some(ClientEvent::DeltaEntity(DeltaEntity {
+) -> Option<DeltaEntity> {
+    Some(DeltaEntity {
         number: entnum,
         model_index: if bits & EntityStateBits::MODEL != 0 {
             Some(cur.read_u8().ok()?)
@@ -347,35 +439,1115 @@ fn parse_delta_entity<T: AsRef<[u8]>>(
         } else {
             None
         },
-    }))
+        remove: bits & EntityStateBits::REMOVE != 0,
+    })
 }
 
+// both of these are signed on the wire.
 fn parse_angle<T: AsRef<[u8]>>(p0: &mut Cursor<T>) -> Option<f32> {
-    Some((p0.read_u8().ok()? as f32) * 360.0 / 256.0)
-}
-
-fn parse_coord<T: AsRef<[u8]>>(p0: &mut Cursor<T>) -> Option<f32> {
-    Some((p0.read_u16::<LittleEndian>().ok()? as f32) / 8.0)
-}
-
-// fn parse_frame<T: AsRef<[u8]>>(entnum: i16, bits: u32, cur: &mut Cursor<T>) -> Option<ClientEvent> {
-//     let _currentframe = cur.read_u32::<LittleEndian>().ok()?;
-//     let _deltaframe = cur.read_u32::<LittleEndian>().ok()?;
-//     let _supressed = cur.read_u8().ok()?; // ?? we don't do anything with this?
-//
-//     // new protocol will tend to check deltas and whatever. we don't care because we're protocol 34 baby
-//     let areabits_len = cur.read_u8().ok()?;
-//     let areabits = [0u8; 32];
-//     cur.read_exact(&mut areabits[..areabits_len])?;
-//
-//     // xxx: what are these areabits for?? "portalarea visibility bits" the hell does that mean
-//     // has something to do with visibility?? i suppose??
-//
-//     // parse playerstate
-//
-//     // parse packetentities
-//
-//     // deltaframe??
-//
-//     Some(ClientEvent())
-// }
+    Some((p0.read_i8().ok()? as f32) * 360.0 / 256.0)
+}
+
+pub(crate) fn parse_coord<T: AsRef<[u8]>>(p0: &mut Cursor<T>) -> Option<f32> {
+    Some((p0.read_i16::<LittleEndian>().ok()? as f32) / 8.0)
+}
+
+// which parts of the player state are in a svc_playerinfo.
+pub enum PlayerStateBits {
+    MTYPE = (1 << 0),
+    MORIGIN = (1 << 1),
+    MVELOCITY = (1 << 2),
+    MTIME = (1 << 3),
+    MFLAGS = (1 << 4),
+    MGRAVITY = (1 << 5),
+    MDELTAANGLES = (1 << 6),
+    VIEWOFFSET = (1 << 7),
+    VIEWANGLES = (1 << 8),
+    KICKANGLES = (1 << 9),
+    BLEND = (1 << 10),
+    FOV = (1 << 11),
+    WEAPONINDEX = (1 << 12),
+    WEAPONFRAME = (1 << 13),
+    RDFLAGS = (1 << 14),
+}
+
+impl BitAnd<PlayerStateBits> for u16 {
+    type Output = u16;
+
+    fn bitand(self, rhs: PlayerStateBits) -> Self::Output {
+        self & (rhs as u16)
+    }
+}
+
+// where things are in the configstrings.
+pub const CS_NAME: u16 = 0;
+pub const CS_MAXCLIENTS: u16 = 30;
+pub const CS_MODELS: u16 = 32;
+pub const CS_SOUNDS: u16 = 288;
+pub const CS_IMAGES: u16 = 544;
+pub const CS_LIGHTS: u16 = 800;
+pub const CS_ITEMS: u16 = 1056;
+pub const CS_PLAYERSKINS: u16 = 1312;
+pub const CS_GENERAL: u16 = 1568;
+pub const MAX_CONFIGSTRINGS: u16 = 2080;
+// one CS_PLAYERSKINS each.
+pub const MAX_CLIENTS: usize = 256;
+
+// who's in `slot`, from its playerskin configstring, `name\model/skin`. an empty one is a
+// slot that was left.
+pub fn player_name(configstrings: &HashMap<u16, Vec<u8>>, slot: u16) -> Option<String> {
+    let skin = configstrings.get(&(CS_PLAYERSKINS + slot))?;
+    let name = skin.split(|b| *b == b'\\').next()?;
+    (!name.is_empty()).then(|| decode(name))
+}
+
+// everyone in the playerskins configstrings.
+pub fn player_names(configstrings: &HashMap<u16, Vec<u8>>) -> Vec<String> {
+    (0..MAX_CLIENTS as u16)
+        .filter_map(|slot| player_name(configstrings, slot))
+        .collect()
+}
+
+pub const MAX_STATS: usize = 32;
+// the stats the stock game dll uses. mods mostly keep these where they are.
+pub const STAT_HEALTH: usize = 1;
+pub const STAT_AMMO: usize = 3;
+pub const STAT_ARMOR: usize = 5;
+pub const STAT_PICKUP_ICON: usize = 7;
+// a configstring index, CS_ITEMS + the item.
+pub const STAT_PICKUP_STRING: usize = 8;
+pub const STAT_FRAGS: usize = 14;
+pub const MAX_ITEMS: usize = 256;
+
+// same deal as DeltaEntity: fields that are not None are fields that changed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlayerState {
+    pub pm_type: Option<u8>,
+    pub origin: Option<[f32; 3]>,
+    pub velocity: Option<[f32; 3]>,
+    pub pm_time: Option<u8>,
+    pub pm_flags: Option<u8>,
+    pub gravity: Option<i16>,
+    pub delta_angles: Option<[f32; 3]>,
+    pub view_offset: Option<[f32; 3]>,
+    pub view_angles: Option<[f32; 3]>,
+    pub kick_angles: Option<[f32; 3]>,
+    pub gun_index: Option<u8>,
+    pub gun_frame: Option<u8>,
+    pub gun_offset: Option<[f32; 3]>,
+    pub gun_angles: Option<[f32; 3]>,
+    pub blend: Option<[f32; 4]>,
+    pub fov: Option<u8>,
+    pub rdflags: Option<u8>,
+    pub stats: [Option<i16>; MAX_STATS],
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameMessage {
+    pub server_frame: i32,
+    // the frame this one is a delta from. -1 means it's from the baselines.
+    pub delta_frame: i32,
+    pub suppress_count: u8,
+    pub area_bits: Vec<u8>,
+    pub player_state: PlayerState,
+    pub entities: Vec<DeltaEntity>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MuzzleFlash {
+    pub entity: i16,
+    // one of the MZ_ values.
+    pub weapon: u8,
+    pub silenced: bool,
+}
+
+impl MuzzleFlash {
+    // the player's slot, since player entities are numbered from 1. None if the entity
+    // isn't a player.
+    pub fn player(&self) -> Option<u8> {
+        if self.entity < 1 || self.entity as usize > MAX_CLIENTS {
+            return None;
+        }
+
+        Some((self.entity - 1) as u8)
+    }
+
+    // what was fired. None for the flashes that aren't shots: logins, respawns and the like.
+    pub fn weapon_name(&self) -> Option<&'static str> {
+        Some(match self.weapon {
+            0 => "blaster",
+            1 => "machinegun",
+            2 => "shotgun",
+            3..=5 => "chaingun",
+            6 => "railgun",
+            7 => "rocket launcher",
+            8 => "grenade launcher",
+            12 => "bfg10k",
+            13 => "super shotgun",
+            14 => "hyperblaster",
+            16 => "ionripper",
+            17 => "blue hyperblaster",
+            18 => "phalanx",
+            30 => "etf rifle",
+            32 => "shotgun",
+            33 => "plasma beam",
+            34 => "blaster",
+            35 => "disruptor",
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SoundMessage {
+    pub sound_index: u8,
+    pub volume: Option<u8>,
+    pub attenuation: Option<u8>,
+    pub time_offset: Option<u8>,
+    pub entity: Option<i16>,
+    pub channel: Option<u8>,
+    pub position: Option<[f32; 3]>,
+}
+
+pub(crate) fn parse_coords<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Option<[f32; 3]> {
+    Some([parse_coord(cur)?, parse_coord(cur)?, parse_coord(cur)?])
+}
+
+pub(crate) fn parse_angle16<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Option<f32> {
+    Some((cur.read_i16::<LittleEndian>().ok()? as f32) * 360.0 / 65536.0)
+}
+
+// a vector of signed chars, scaled.
+pub(crate) fn parse_char_vec<T: AsRef<[u8]>>(cur: &mut Cursor<T>, scale: f32) -> Option<[f32; 3]> {
+    Some([
+        cur.read_i8().ok()? as f32 * scale,
+        cur.read_i8().ok()? as f32 * scale,
+        cur.read_i8().ok()? as f32 * scale,
+    ])
+}
+
+impl PlayerState {
+    // bring this up to date with a delta.
+    pub fn apply(&mut self, delta: &PlayerState) {
+        fn take<T: Copy>(field: &mut Option<T>, delta: Option<T>) {
+            if delta.is_some() {
+                *field = delta;
+            }
+        }
+
+        take(&mut self.pm_type, delta.pm_type);
+        take(&mut self.origin, delta.origin);
+        take(&mut self.velocity, delta.velocity);
+        take(&mut self.pm_time, delta.pm_time);
+        take(&mut self.pm_flags, delta.pm_flags);
+        take(&mut self.gravity, delta.gravity);
+        take(&mut self.delta_angles, delta.delta_angles);
+        take(&mut self.view_offset, delta.view_offset);
+        take(&mut self.view_angles, delta.view_angles);
+        take(&mut self.kick_angles, delta.kick_angles);
+        take(&mut self.gun_index, delta.gun_index);
+        take(&mut self.gun_frame, delta.gun_frame);
+        take(&mut self.gun_offset, delta.gun_offset);
+        take(&mut self.gun_angles, delta.gun_angles);
+        take(&mut self.blend, delta.blend);
+        take(&mut self.fov, delta.fov);
+        take(&mut self.rdflags, delta.rdflags);
+        for (stat, delta) in self.stats.iter_mut().zip(delta.stats) {
+            take(stat, delta);
+        }
+    }
+
+    // what has to be sent to get from `from` to this.
+    pub fn delta_from(&self, from: &PlayerState) -> PlayerState {
+        fn diff<T: Copy + PartialEq>(field: Option<T>, from: Option<T>) -> Option<T> {
+            if field != from {
+                field
+            } else {
+                None
+            }
+        }
+
+        let mut stats = [None; MAX_STATS];
+        for (i, stat) in stats.iter_mut().enumerate() {
+            *stat = diff(self.stats[i], from.stats[i]);
+        }
+
+        PlayerState {
+            pm_type: diff(self.pm_type, from.pm_type),
+            origin: diff(self.origin, from.origin),
+            velocity: diff(self.velocity, from.velocity),
+            pm_time: diff(self.pm_time, from.pm_time),
+            pm_flags: diff(self.pm_flags, from.pm_flags),
+            gravity: diff(self.gravity, from.gravity),
+            delta_angles: diff(self.delta_angles, from.delta_angles),
+            view_offset: diff(self.view_offset, from.view_offset),
+            view_angles: diff(self.view_angles, from.view_angles),
+            kick_angles: diff(self.kick_angles, from.kick_angles),
+            gun_index: diff(self.gun_index, from.gun_index),
+            gun_frame: diff(self.gun_frame, from.gun_frame),
+            gun_offset: diff(self.gun_offset, from.gun_offset),
+            gun_angles: diff(self.gun_angles, from.gun_angles),
+            blend: diff(self.blend, from.blend),
+            fov: diff(self.fov, from.fov),
+            rdflags: diff(self.rdflags, from.rdflags),
+            stats,
+        }
+    }
+}
+
+pub fn parse_player_state<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Option<PlayerState> {
+    let flags = cur.read_u16::<LittleEndian>().ok()?;
+    let mut ps = PlayerState::default();
+
+    if flags & PlayerStateBits::MTYPE != 0 {
+        ps.pm_type = Some(cur.read_u8().ok()?);
+    }
+    if flags & PlayerStateBits::MORIGIN != 0 {
+        ps.origin = Some(parse_coords(cur)?);
+    }
+    if flags & PlayerStateBits::MVELOCITY != 0 {
+        ps.velocity = Some(parse_coords(cur)?);
+    }
+    if flags & PlayerStateBits::MTIME != 0 {
+        ps.pm_time = Some(cur.read_u8().ok()?);
+    }
+    if flags & PlayerStateBits::MFLAGS != 0 {
+        ps.pm_flags = Some(cur.read_u8().ok()?);
+    }
+    if flags & PlayerStateBits::MGRAVITY != 0 {
+        ps.gravity = Some(cur.read_i16::<LittleEndian>().ok()?);
+    }
+    if flags & PlayerStateBits::MDELTAANGLES != 0 {
+        ps.delta_angles = Some([
+            parse_angle16(cur)?,
+            parse_angle16(cur)?,
+            parse_angle16(cur)?,
+        ]);
+    }
+    if flags & PlayerStateBits::VIEWOFFSET != 0 {
+        ps.view_offset = Some(parse_char_vec(cur, 0.25)?);
+    }
+    if flags & PlayerStateBits::VIEWANGLES != 0 {
+        ps.view_angles = Some([
+            parse_angle16(cur)?,
+            parse_angle16(cur)?,
+            parse_angle16(cur)?,
+        ]);
+    }
+    if flags & PlayerStateBits::KICKANGLES != 0 {
+        ps.kick_angles = Some(parse_char_vec(cur, 0.25)?);
+    }
+    if flags & PlayerStateBits::WEAPONINDEX != 0 {
+        ps.gun_index = Some(cur.read_u8().ok()?);
+    }
+    if flags & PlayerStateBits::WEAPONFRAME != 0 {
+        ps.gun_frame = Some(cur.read_u8().ok()?);
+        ps.gun_offset = Some(parse_char_vec(cur, 0.25)?);
+        ps.gun_angles = Some(parse_char_vec(cur, 0.25)?);
+    }
+    if flags & PlayerStateBits::BLEND != 0 {
+        ps.blend = Some([
+            cur.read_u8().ok()? as f32 / 255.0,
+            cur.read_u8().ok()? as f32 / 255.0,
+            cur.read_u8().ok()? as f32 / 255.0,
+            cur.read_u8().ok()? as f32 / 255.0,
+        ]);
+    }
+    if flags & PlayerStateBits::FOV != 0 {
+        ps.fov = Some(cur.read_u8().ok()?);
+    }
+    if flags & PlayerStateBits::RDFLAGS != 0 {
+        ps.rdflags = Some(cur.read_u8().ok()?);
+    }
+
+    let stat_bits = cur.read_u32::<LittleEndian>().ok()?;
+    for (i, stat) in ps.stats.iter_mut().enumerate() {
+        if stat_bits & (1 << i) != 0 {
+            *stat = Some(cur.read_i16::<LittleEndian>().ok()?);
+        }
+    }
+
+    Some(ps)
+}
+
+// entity updates until entity number 0.
+pub fn parse_packet_entities<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Option<Vec<DeltaEntity>> {
+    let mut entities = Vec::new();
+
+    loop {
+        let (number, bits) = parse_entity_bits(cur)?;
+        if number == 0 {
+            break;
+        }
+
+        entities.push(parse_delta_entity(number, bits, cur)?);
+    }
+
+    Some(entities)
+}
+
+// svc_frame is always followed by a svc_playerinfo and a svc_packetentities, cl_parseframe
+// reads them as part of the frame.
+pub fn parse_frame<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Result<ClientEvent, Q2ProtoError> {
+    read_frame(cur)
+        .map(|frame| ClientEvent::Frame(Box::new(frame)))
+        .ok_or(Q2ProtoError::Truncated(ServerToClientOps::Frame))
+}
+
+fn read_frame<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Option<FrameMessage> {
+    let server_frame = cur.read_i32::<LittleEndian>().ok()?;
+    let delta_frame = cur.read_i32::<LittleEndian>().ok()?;
+    let suppress_count = cur.read_u8().ok()?;
+
+    let mut area_bits = vec![0u8; cur.read_u8().ok()? as usize];
+    cur.read_exact(&mut area_bits).ok()?;
+
+    if ServerToClientOps::from(cur.read_u8().ok()?) != ServerToClientOps::PlayerInfo {
+        return None;
+    }
+    let player_state = parse_player_state(cur)?;
+
+    if ServerToClientOps::from(cur.read_u8().ok()?) != ServerToClientOps::PacketEntities {
+        return None;
+    }
+    let entities = parse_packet_entities(cur)?;
+
+    Some(FrameMessage {
+        server_frame,
+        delta_frame,
+        suppress_count,
+        area_bits,
+        player_state,
+        entities,
+    })
+}
+
+pub fn parse_muzzle_flash<T: AsRef<[u8]>>(
+    cur: &mut Cursor<T>,
+) -> Result<ClientEvent, Q2ProtoError> {
+    let truncated = |_| Q2ProtoError::Truncated(ServerToClientOps::MuzzleFlash);
+    let entity = cur.read_i16::<LittleEndian>().map_err(truncated)?;
+    let weapon = cur.read_u8().map_err(truncated)?;
+
+    Ok(ClientEvent::MuzzleFlash(MuzzleFlash {
+        entity,
+        weapon: weapon & !MZ_SILENCED,
+        silenced: weapon & MZ_SILENCED != 0,
+    }))
+}
+
+// monsters. the flash number indexes monster_flash_offset.
+pub fn parse_muzzle_flash2<T: AsRef<[u8]>>(
+    cur: &mut Cursor<T>,
+) -> Result<ClientEvent, Q2ProtoError> {
+    let truncated = |_| Q2ProtoError::Truncated(ServerToClientOps::MuzzleFlash2);
+
+    Ok(ClientEvent::MuzzleFlash2(
+        cur.read_i16::<LittleEndian>().map_err(truncated)?,
+        cur.read_u8().map_err(truncated)?,
+    ))
+}
+
+const MZ_SILENCED: u8 = 128;
+
+pub(crate) const SND_VOLUME: u8 = 1 << 0;
+pub(crate) const SND_ATTENUATION: u8 = 1 << 1;
+const SND_POS: u8 = 1 << 2;
+const SND_ENT: u8 = 1 << 3;
+pub(crate) const SND_OFFSET: u8 = 1 << 4;
+
+pub fn parse_sound<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Result<ClientEvent, Q2ProtoError> {
+    read_sound(cur)
+        .map(ClientEvent::Sound)
+        .ok_or(Q2ProtoError::Truncated(ServerToClientOps::Sound))
+}
+
+fn read_sound<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Option<SoundMessage> {
+    let flags = cur.read_u8().ok()?;
+    let mut sound = SoundMessage {
+        sound_index: cur.read_u8().ok()?,
+        ..Default::default()
+    };
+
+    if flags & SND_VOLUME != 0 {
+        sound.volume = Some(cur.read_u8().ok()?);
+    }
+    if flags & SND_ATTENUATION != 0 {
+        sound.attenuation = Some(cur.read_u8().ok()?);
+    }
+    if flags & SND_OFFSET != 0 {
+        sound.time_offset = Some(cur.read_u8().ok()?);
+    }
+    if flags & SND_ENT != 0 {
+        // entity number in the top 13 bits, channel in the bottom 3.
+        let ent_channel = cur.read_u16::<LittleEndian>().ok()?;
+        sound.entity = Some((ent_channel >> 3) as i16);
+        sound.channel = Some((ent_channel & 7) as u8);
+    }
+    if flags & SND_POS != 0 {
+        sound.position = Some(parse_coords(cur)?);
+    }
+
+    Some(sound)
+}
+
+// we don't do anything with temp entities but hand them over, but we need to know how long
+// each kind is to get past them. these are the sizes cl_parsetent reads.
+fn temp_entity_size<T: AsRef<[u8]>>(kind: u8, cur: &mut Cursor<T>) -> Result<usize, Q2ProtoError> {
+    const POS: usize = 6;
+    const DIR: usize = 1;
+
+    Ok(match kind {
+        // gunshot, blood, blaster, shotgun, sparks, screen/shield/bullet sparks, greenblood,
+        // blaster2, moreblood, heatbeam sparks/steam, electric sparks, flechette
+        0 | 1 | 2 | 4 | 9 | 12 | 13 | 14 | 26 | 30 | 42 | 43 | 44 | 46 | 55 => POS + DIR,
+        // splash, laser/welding/tunnel sparks: count, pos, dir, color
+        10 | 15 | 25 | 29 => 1 + POS + DIR + 1,
+        // railtrail, bubbletrail, bfg laser, bluehyperblaster, debugtrail, bubbletrail2
+        3 | 11 | 23 | 27 | 34 | 41 => POS + POS,
+        // the explosions, bosstport, chainfist smoke, teleport effect, dball goal, nukeblast,
+        // widowsplash
+        5 | 6 | 7 | 8 | 17 | 18 | 20 | 21 | 22 | 28 | 35 | 45 | 47 | 48 | 49 | 51 | 52 | 53
+        | 54 => POS,
+        // parasite/medic cable, heatbeams: entity, start, end
+        16 | 19 | 38 | 39 => 2 + POS + POS,
+        // grapple cable: entity, start, end, offset
+        24 => 2 + POS + POS + POS,
+        // lightning: two entities, start, end
+        33 => 2 + 2 + POS + POS,
+        // flashlight: pos, entity
+        36 => POS + 2,
+        // forcewall: start, end, color
+        37 => POS + POS + 1,
+        // widowbeamout: id, pos
+        50 => 2 + POS,
+        // steam: id, count, pos, dir, color, magnitude, and an interval if it has an id
+        40 => {
+            let id = cur
+                .read_i16::<LittleEndian>()
+                .map_err(|_| Q2ProtoError::Truncated(ServerToClientOps::TempEntity))?;
+            cur.set_position(cur.position() - 2);
+            2 + 1 + POS + DIR + 1 + 2 + if id != -1 { 4 } else { 0 }
+        }
+        // not a short read, we just don't know how far to skip.
+        _ => {
+            return Err(Q2ProtoError::MalformedResponse(format!(
+                "unknown temp entity {}",
+                kind
+            )))
+        }
+    })
+}
+
+pub fn parse_temp_entity<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Result<ClientEvent, Q2ProtoError> {
+    let truncated = || Q2ProtoError::Truncated(ServerToClientOps::TempEntity);
+
+    let kind = cur.read_u8().map_err(|_| truncated())?;
+    let size = temp_entity_size(kind, cur)?;
+    let mut data = vec![0u8; size];
+    cur.read_exact(&mut data).map_err(|_| truncated())?;
+
+    Ok(ClientEvent::TempEntity(kind, data))
+}
+
+pub fn parse_inventory<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Result<ClientEvent, Q2ProtoError> {
+    let mut inventory = Vec::with_capacity(MAX_ITEMS);
+    for _ in 0..MAX_ITEMS {
+        inventory.push(
+            cur.read_i16::<LittleEndian>()
+                .map_err(|_| Q2ProtoError::Truncated(ServerToClientOps::Inventory))?,
+        );
+    }
+
+    Ok(ClientEvent::Inventory(inventory))
+}
+
+// nothing comes of this one, we just get past it.
+pub fn skip_download<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Result<(), Q2ProtoError> {
+    let truncated = |_| Q2ProtoError::Truncated(ServerToClientOps::Download);
+
+    let size = cur.read_i16::<LittleEndian>().map_err(truncated)?;
+    cur.read_u8().map_err(truncated)?; // percent
+    if size > 0 {
+        let mut data = vec![0u8; size as usize];
+        cur.read_exact(&mut data).map_err(truncated)?;
+    }
+
+    Ok(())
+}
+
+// the other direction, for writing demos. these go into a Vec so they can't fail.
+
+pub fn write_string(buf: &mut Vec<u8>, str: &[u8]) {
+    buf.extend_from_slice(str);
+    buf.push(0);
+}
+
+pub fn write_serverdata(buf: &mut Vec<u8>, serverdata: &ServerDataMessage) {
+    buf.push(ServerToClientOps::ServerData as u8);
+    let _ = buf.write_u32::<LittleEndian>(serverdata.protocol);
+    let _ = buf.write_u32::<LittleEndian>(serverdata.srv_count);
+    buf.push(serverdata.attract_loop);
+    write_string(buf, serverdata.gamedir.as_bytes());
+    let _ = buf.write_u16::<LittleEndian>(serverdata.clnum);
+    write_string(buf, serverdata.levelname.as_bytes());
+}
+
+pub fn write_configstring(buf: &mut Vec<u8>, index: u16, value: &[u8]) {
+    buf.push(ServerToClientOps::ConfigString as u8);
+    let _ = buf.write_u16::<LittleEndian>(index);
+    write_string(buf, value);
+}
+
+pub fn write_baseline(buf: &mut Vec<u8>, ent: &DeltaEntity) {
+    buf.push(ServerToClientOps::SpawnBaseline as u8);
+    write_delta_entity(buf, ent);
+}
+
+pub fn write_stufftext(buf: &mut Vec<u8>, text: &[u8]) {
+    buf.push(ServerToClientOps::StuffText as u8);
+    write_string(buf, text);
+}
+
+fn write_coord(buf: &mut Vec<u8>, v: f32) {
+    let _ = buf.write_i16::<LittleEndian>((v * 8.0) as i16);
+}
+
+fn write_angle(buf: &mut Vec<u8>, v: f32) {
+    buf.push(((v * 256.0 / 360.0) as i32 & 255) as u8);
+}
+
+// picks the 8/16/32 bit encoding the same way msg_writedeltaentity does.
+fn sized_bits(v: u32, bits8: EntityStateBits, bits16: EntityStateBits) -> u32 {
+    if v < 0x100 {
+        bits8 as u32
+    } else if v < 0x10000 {
+        bits16 as u32
+    } else {
+        bits8 | bits16
+    }
+}
+
+fn write_sized(buf: &mut Vec<u8>, v: u32, bits: u32, bits8: EntityStateBits) {
+    let size8 = bits8 as u32;
+    if bits == size8 {
+        buf.push(v as u8);
+    } else if bits & size8 == 0 {
+        let _ = buf.write_u16::<LittleEndian>(v as u16);
+    } else {
+        let _ = buf.write_u32::<LittleEndian>(v);
+    }
+}
+
+// whatever is Some in the entity gets written, the way parse_delta_entity reads it back.
+pub fn write_delta_entity(buf: &mut Vec<u8>, ent: &DeltaEntity) {
+    let mut bits: u32 = 0;
+    let mut set = |cond: bool, bit: EntityStateBits| {
+        if cond {
+            bits |= bit as u32;
+        }
+    };
+
+    set(ent.model_index.is_some(), EntityStateBits::MODEL);
+    set(ent.model_index2.is_some(), EntityStateBits::MODEL2);
+    set(ent.model_index3.is_some(), EntityStateBits::MODEL3);
+    set(ent.model_index4.is_some(), EntityStateBits::MODEL4);
+    set(
+        ent.frame.is_some_and(|f| (0..0x100).contains(&f)),
+        EntityStateBits::FRAME8,
+    );
+    set(
+        ent.frame.is_some_and(|f| !(0..0x100).contains(&f)),
+        EntityStateBits::FRAME16,
+    );
+    set(ent.origin0.is_some(), EntityStateBits::ORIGIN1);
+    set(ent.origin1.is_some(), EntityStateBits::ORIGIN2);
+    set(ent.origin2.is_some(), EntityStateBits::ORIGIN3);
+    set(ent.angle0.is_some(), EntityStateBits::ANGLE1);
+    set(ent.angle1.is_some(), EntityStateBits::ANGLE2);
+    set(ent.angle2.is_some(), EntityStateBits::ANGLE3);
+    set(
+        ent.old_origin0.is_some() && ent.old_origin1.is_some() && ent.old_origin2.is_some(),
+        EntityStateBits::OLDORIGIN,
+    );
+    set(ent.sound.is_some(), EntityStateBits::SOUND);
+    set(ent.event != 0, EntityStateBits::EVENT);
+    set(ent.solid.is_some(), EntityStateBits::SOLID);
+    set(ent.remove, EntityStateBits::REMOVE);
+    set(ent.number >= 0x100, EntityStateBits::NUMBER16);
+
+    let skin_bits = ent.skin.map_or(0, |v| {
+        sized_bits(v, EntityStateBits::SKIN8, EntityStateBits::SKIN16)
+    });
+    let effects_bits = ent.effects.map_or(0, |v| {
+        sized_bits(v, EntityStateBits::EFFECTS8, EntityStateBits::EFFECTS16)
+    });
+    let render_fx_bits = ent.render_fx.map_or(0, |v| {
+        sized_bits(v, EntityStateBits::RENDERFX8, EntityStateBits::RENDERFX16)
+    });
+    bits |= skin_bits | effects_bits | render_fx_bits;
+
+    if bits & 0xff000000 != 0 {
+        bits |= EntityStateBits::MOREBITS3 | EntityStateBits::MOREBITS2;
+        bits |= EntityStateBits::MOREBITS1 as u32;
+    } else if bits & 0x00ff0000 != 0 {
+        bits |= EntityStateBits::MOREBITS2 | EntityStateBits::MOREBITS1;
+    } else if bits & 0x0000ff00 != 0 {
+        bits |= EntityStateBits::MOREBITS1 as u32;
+    }
+
+    buf.push(bits as u8);
+    if bits & EntityStateBits::MOREBITS1 != 0 {
+        buf.push((bits >> 8) as u8);
+    }
+    if bits & EntityStateBits::MOREBITS2 != 0 {
+        buf.push((bits >> 16) as u8);
+    }
+    if bits & EntityStateBits::MOREBITS3 != 0 {
+        buf.push((bits >> 24) as u8);
+    }
+
+    if bits & EntityStateBits::NUMBER16 != 0 {
+        let _ = buf.write_i16::<LittleEndian>(ent.number);
+    } else {
+        buf.push(ent.number as u8);
+    }
+
+    for model in [
+        ent.model_index,
+        ent.model_index2,
+        ent.model_index3,
+        ent.model_index4,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        buf.push(model);
+    }
+    if let Some(frame) = ent.frame {
+        if bits & EntityStateBits::FRAME8 != 0 {
+            buf.push(frame as u8);
+        } else {
+            let _ = buf.write_i16::<LittleEndian>(frame);
+        }
+    }
+    if let Some(skin) = ent.skin {
+        write_sized(buf, skin, skin_bits, EntityStateBits::SKIN8);
+    }
+    if let Some(effects) = ent.effects {
+        write_sized(buf, effects, effects_bits, EntityStateBits::EFFECTS8);
+    }
+    if let Some(render_fx) = ent.render_fx {
+        write_sized(buf, render_fx, render_fx_bits, EntityStateBits::RENDERFX8);
+    }
+    for coord in [ent.origin0, ent.origin1, ent.origin2]
+        .into_iter()
+        .flatten()
+    {
+        write_coord(buf, coord);
+    }
+    for angle in [ent.angle0, ent.angle1, ent.angle2].into_iter().flatten() {
+        write_angle(buf, angle);
+    }
+    if bits & EntityStateBits::OLDORIGIN != 0 {
+        for coord in [ent.old_origin0, ent.old_origin1, ent.old_origin2]
+            .into_iter()
+            .flatten()
+        {
+            write_coord(buf, coord);
+        }
+    }
+    if let Some(sound) = ent.sound {
+        buf.push(sound);
+    }
+    if ent.event != 0 {
+        buf.push(ent.event);
+    }
+    if let Some(solid) = ent.solid {
+        let _ = buf.write_u16::<LittleEndian>(solid as u16);
+    }
+}
+
+pub fn write_print(buf: &mut Vec<u8>, level: PrintLevel, text: &[u8]) {
+    buf.push(ServerToClientOps::Print as u8);
+    buf.push(level as u8);
+    write_string(buf, text);
+}
+
+pub fn write_sound(buf: &mut Vec<u8>, sound: &SoundMessage) {
+    let mut flags = 0;
+    if sound.volume.is_some() {
+        flags |= SND_VOLUME;
+    }
+    if sound.attenuation.is_some() {
+        flags |= SND_ATTENUATION;
+    }
+    if sound.time_offset.is_some() {
+        flags |= SND_OFFSET;
+    }
+    if sound.entity.is_some() {
+        flags |= SND_ENT;
+    }
+    if sound.position.is_some() {
+        flags |= SND_POS;
+    }
+
+    buf.push(ServerToClientOps::Sound as u8);
+    buf.push(flags);
+    buf.push(sound.sound_index);
+    buf.extend(sound.volume);
+    buf.extend(sound.attenuation);
+    buf.extend(sound.time_offset);
+    if let Some(entity) = sound.entity {
+        let channel = sound.channel.unwrap_or(0) as u16 & 7;
+        let _ = buf.write_u16::<LittleEndian>((entity as u16) << 3 | channel);
+    }
+    for coord in sound.position.into_iter().flatten() {
+        write_coord(buf, coord);
+    }
+}
+
+pub fn write_muzzle_flash(buf: &mut Vec<u8>, flash: &MuzzleFlash) {
+    buf.push(ServerToClientOps::MuzzleFlash as u8);
+    let _ = buf.write_i16::<LittleEndian>(flash.entity);
+    buf.push(if flash.silenced {
+        flash.weapon | MZ_SILENCED
+    } else {
+        flash.weapon
+    });
+}
+
+pub fn write_muzzle_flash2(buf: &mut Vec<u8>, entity: i16, flash: u8) {
+    buf.push(ServerToClientOps::MuzzleFlash2 as u8);
+    let _ = buf.write_i16::<LittleEndian>(entity);
+    buf.push(flash);
+}
+
+pub fn write_temp_entity(buf: &mut Vec<u8>, kind: u8, data: &[u8]) {
+    buf.push(ServerToClientOps::TempEntity as u8);
+    buf.push(kind);
+    buf.extend_from_slice(data);
+}
+
+pub fn write_inventory(buf: &mut Vec<u8>, inventory: &[i16]) {
+    buf.push(ServerToClientOps::Inventory as u8);
+    for i in 0..MAX_ITEMS {
+        let _ = buf.write_i16::<LittleEndian>(inventory.get(i).copied().unwrap_or(0));
+    }
+}
+
+fn write_angle16(buf: &mut Vec<u8>, v: f32) {
+    let _ = buf.write_i16::<LittleEndian>((v * 65536.0 / 360.0).round() as i32 as i16);
+}
+
+fn write_char_vec(buf: &mut Vec<u8>, v: [f32; 3], scale: f32) {
+    for c in v {
+        buf.push((c / scale).round() as i8 as u8);
+    }
+}
+
+// just the flags and fields, like parse_player_state reads them.
+pub fn write_player_state(buf: &mut Vec<u8>, ps: &PlayerState) {
+    let mut flags: u16 = 0;
+    let mut set = |cond: bool, bit: PlayerStateBits| {
+        if cond {
+            flags |= bit as u16;
+        }
+    };
+
+    set(ps.pm_type.is_some(), PlayerStateBits::MTYPE);
+    set(ps.origin.is_some(), PlayerStateBits::MORIGIN);
+    set(ps.velocity.is_some(), PlayerStateBits::MVELOCITY);
+    set(ps.pm_time.is_some(), PlayerStateBits::MTIME);
+    set(ps.pm_flags.is_some(), PlayerStateBits::MFLAGS);
+    set(ps.gravity.is_some(), PlayerStateBits::MGRAVITY);
+    set(ps.delta_angles.is_some(), PlayerStateBits::MDELTAANGLES);
+    set(ps.view_offset.is_some(), PlayerStateBits::VIEWOFFSET);
+    set(ps.view_angles.is_some(), PlayerStateBits::VIEWANGLES);
+    set(ps.kick_angles.is_some(), PlayerStateBits::KICKANGLES);
+    set(ps.blend.is_some(), PlayerStateBits::BLEND);
+    set(ps.fov.is_some(), PlayerStateBits::FOV);
+    set(ps.gun_index.is_some(), PlayerStateBits::WEAPONINDEX);
+    // the gun frame, offset and angles all go together.
+    set(
+        ps.gun_frame.is_some() || ps.gun_offset.is_some() || ps.gun_angles.is_some(),
+        PlayerStateBits::WEAPONFRAME,
+    );
+    set(ps.rdflags.is_some(), PlayerStateBits::RDFLAGS);
+
+    let _ = buf.write_u16::<LittleEndian>(flags);
+    buf.extend(ps.pm_type);
+    for coord in ps.origin.into_iter().chain(ps.velocity).flatten() {
+        write_coord(buf, coord);
+    }
+    buf.extend(ps.pm_time);
+    buf.extend(ps.pm_flags);
+    if let Some(gravity) = ps.gravity {
+        let _ = buf.write_i16::<LittleEndian>(gravity);
+    }
+    for angle in ps.delta_angles.into_iter().flatten() {
+        write_angle16(buf, angle);
+    }
+    if let Some(view_offset) = ps.view_offset {
+        write_char_vec(buf, view_offset, 0.25);
+    }
+    for angle in ps.view_angles.into_iter().flatten() {
+        write_angle16(buf, angle);
+    }
+    if let Some(kick_angles) = ps.kick_angles {
+        write_char_vec(buf, kick_angles, 0.25);
+    }
+    buf.extend(ps.gun_index);
+    if flags & PlayerStateBits::WEAPONFRAME != 0 {
+        buf.push(ps.gun_frame.unwrap_or(0));
+        write_char_vec(buf, ps.gun_offset.unwrap_or_default(), 0.25);
+        write_char_vec(buf, ps.gun_angles.unwrap_or_default(), 0.25);
+    }
+    for c in ps.blend.into_iter().flatten() {
+        buf.push((c * 255.0).round() as u8);
+    }
+    buf.extend(ps.fov);
+    buf.extend(ps.rdflags);
+
+    let mut stat_bits: u32 = 0;
+    for (i, stat) in ps.stats.iter().enumerate() {
+        if stat.is_some() {
+            stat_bits |= 1 << i;
+        }
+    }
+    let _ = buf.write_u32::<LittleEndian>(stat_bits);
+    for stat in ps.stats.iter().flatten() {
+        let _ = buf.write_i16::<LittleEndian>(*stat);
+    }
+}
+
+// svc_frame with its svc_playerinfo and svc_packetentities.
+pub fn write_frame(buf: &mut Vec<u8>, frame: &FrameMessage) {
+    buf.push(ServerToClientOps::Frame as u8);
+    let _ = buf.write_i32::<LittleEndian>(frame.server_frame);
+    let _ = buf.write_i32::<LittleEndian>(frame.delta_frame);
+    buf.push(frame.suppress_count);
+    buf.push(frame.area_bits.len() as u8);
+    buf.extend_from_slice(&frame.area_bits);
+
+    buf.push(ServerToClientOps::PlayerInfo as u8);
+    write_player_state(buf, &frame.player_state);
+
+    buf.push(ServerToClientOps::PacketEntities as u8);
+    for ent in &frame.entities {
+        write_delta_entity(buf, ent);
+    }
+    let _ = buf.write_u16::<LittleEndian>(0); // no more entities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{parse_message, write_event};
+
+    fn round_trip(evt: ClientEvent) {
+        let mut buf = Vec::new();
+        write_event(&mut buf, &evt);
+        assert_eq!(parse_message(&buf).unwrap(), [evt]);
+    }
+
+    fn player_state() -> PlayerState {
+        let mut stats = [None; MAX_STATS];
+        stats[STAT_HEALTH] = Some(100);
+        stats[STAT_FRAGS] = Some(-3);
+        stats[31] = Some(i16::MAX);
+
+        PlayerState {
+            pm_type: Some(1),
+            origin: Some([128.0, -64.5, 24.125]),
+            velocity: Some([300.0, 0.0, -12.25]),
+            pm_time: Some(10),
+            pm_flags: Some(4),
+            gravity: Some(800),
+            delta_angles: Some([0.0, 90.0, -45.0]),
+            view_offset: Some([0.0, 0.0, 22.0]),
+            view_angles: Some([22.5, -90.0, 0.0]),
+            kick_angles: Some([-1.5, 0.25, 0.0]),
+            gun_index: Some(7),
+            gun_frame: Some(12),
+            gun_offset: Some([0.5, 0.0, -0.75]),
+            gun_angles: Some([0.0, 1.0, 0.0]),
+            blend: Some([1.0, 0.0, 51.0 / 255.0, 0.0]),
+            fov: Some(90),
+            rdflags: Some(2),
+            stats,
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let entities = vec![
+            DeltaEntity {
+                number: 1,
+                model_index: Some(255),
+                frame: Some(40),
+                origin0: Some(-512.0),
+                origin1: Some(8.5),
+                origin2: Some(0.0),
+                angle1: Some(90.0),
+                event: 2,
+                ..Default::default()
+            },
+            // the 16 and 32 bit sizes.
+            DeltaEntity {
+                number: 300,
+                model_index2: Some(3),
+                model_index3: Some(4),
+                model_index4: Some(5),
+                frame: Some(300),
+                skin: Some(70000),
+                effects: Some(0x1234),
+                render_fx: Some(8),
+                old_origin0: Some(1.0),
+                old_origin1: Some(2.0),
+                old_origin2: Some(3.0),
+                sound: Some(9),
+                solid: Some(0x1f1f),
+                ..Default::default()
+            },
+            DeltaEntity {
+                number: 42,
+                remove: true,
+                ..Default::default()
+            },
+        ];
+
+        round_trip(ClientEvent::Frame(Box::new(FrameMessage {
+            server_frame: 1000,
+            delta_frame: 998,
+            suppress_count: 1,
+            area_bits: vec![0xff, 0x01, 0x80],
+            player_state: player_state(),
+            entities,
+        })));
+
+        // a full frame: from the baselines, no player state changes, no entities.
+        round_trip(ClientEvent::Frame(Box::new(FrameMessage {
+            server_frame: 1,
+            delta_frame: -1,
+            suppress_count: 0,
+            area_bits: vec![],
+            player_state: PlayerState::default(),
+            entities: vec![],
+        })));
+    }
+
+    #[test]
+    fn player_state_deltas() {
+        let full = player_state();
+        let mut delta = PlayerState {
+            origin: Some([0.0, 0.0, 0.0]),
+            fov: Some(110),
+            ..Default::default()
+        };
+        delta.stats[STAT_HEALTH] = Some(75);
+
+        for ps in [full.clone(), delta.clone(), PlayerState::default()] {
+            let mut buf = Vec::new();
+            write_player_state(&mut buf, &ps);
+            assert_eq!(parse_player_state(&mut Cursor::new(&buf)), Some(ps));
+        }
+
+        // only what's in the delta changes.
+        let mut applied = full.clone();
+        applied.apply(&delta);
+        assert_eq!(applied.origin, Some([0.0, 0.0, 0.0]));
+        assert_eq!(applied.fov, Some(110));
+        assert_eq!(applied.stats[STAT_HEALTH], Some(75));
+        assert_eq!(applied.velocity, full.velocity);
+        assert_eq!(applied.stats[STAT_FRAGS], Some(-3));
+    }
+
+    #[test]
+    fn sounds() {
+        round_trip(ClientEvent::Sound(SoundMessage {
+            sound_index: 12,
+            ..Default::default()
+        }));
+        round_trip(ClientEvent::Sound(SoundMessage {
+            sound_index: 200,
+            volume: Some(255),
+            attenuation: Some(64),
+            time_offset: Some(3),
+            entity: Some(1023),
+            channel: Some(5),
+            position: Some([-100.0, 2.5, 4000.0]),
+        }));
+    }
+
+    #[test]
+    fn muzzle_flashes() {
+        let flash = MuzzleFlash {
+            entity: 3,
+            weapon: 6,
+            silenced: true,
+        };
+        round_trip(ClientEvent::MuzzleFlash(flash));
+        round_trip(ClientEvent::MuzzleFlash2(70, 93));
+
+        assert_eq!(flash.player(), Some(2));
+        assert_eq!(flash.weapon_name(), Some("railgun"));
+        for entity in [0, -1, MAX_CLIENTS as i16 + 1] {
+            assert_eq!(MuzzleFlash { entity, ..flash }.player(), None);
+        }
+    }
+
+    #[test]
+    fn temp_entity_sizes() {
+        let mut steam_with_id = vec![0u8; 17];
+        steam_with_id[..2].copy_from_slice(&5i16.to_le_bytes());
+        let mut steam = vec![0u8; 13];
+        steam[..2].copy_from_slice(&(-1i16).to_le_bytes());
+
+        // one of each size cl_parsetent knows.
+        let cases = [
+            (0, vec![1u8; 7]),
+            (10, vec![2; 9]),
+            (3, vec![3; 12]),
+            (5, vec![4; 6]),
+            (16, vec![5; 14]),
+            (24, vec![6; 20]),
+            (33, vec![7; 16]),
+            (36, vec![8; 8]),
+            (37, vec![9; 13]),
+            (50, vec![10; 8]),
+            (40, steam_with_id),
+            (40, steam),
+        ];
+
+        for (kind, data) in cases {
+            // if the size is off, the print after it won't parse.
+            let events = vec![
+                ClientEvent::TempEntity(kind, data),
+                ClientEvent::Print(PrintLevel::HIGH, b"next\n".to_vec()),
+            ];
+            let mut buf = Vec::new();
+            for evt in &events {
+                write_event(&mut buf, evt);
+            }
+            assert_eq!(parse_message(&buf).unwrap(), events, "kind {}", kind);
+        }
+    }
+
+    #[test]
+    fn bad_temp_entities() {
+        let mut buf = Vec::new();
+        write_temp_entity(&mut buf, 99, &[0; 6]);
+        assert!(matches!(
+            parse_message(&buf),
+            Err(Q2ProtoError::MalformedResponse(_))
+        ));
+
+        let mut buf = Vec::new();
+        write_temp_entity(&mut buf, 3, &[0; 6]);
+        assert!(matches!(
+            parse_message(&buf),
+            Err(Q2ProtoError::Truncated(ServerToClientOps::TempEntity))
+        ));
+    }
+}
@@ -0,0 +1,243 @@
+use super::error::{Q2ProtoError, RejectReason};
+use super::session::oob_packet;
+use super::text::decode;
+use super::user_info::UserInfo;
+use std::collections::HashMap;
+use std::time::Duration;
+
+// long replies get split over a few packets. once they stop coming for this long, we're done.
+pub(crate) const RCON_MORE_WAIT: Duration = Duration::from_millis(250);
+
+pub(crate) fn rcon_packet(password: &str, cmd: &str) -> Vec<u8> {
+    oob_packet(format!("rcon {} {}", password, cmd).as_bytes())
+}
+
+// one piece of the reply, with the `print\n` taken off.
+pub(crate) fn parse_rcon_reply(payload: &[u8]) -> Result<String, Q2ProtoError> {
+    let text = decode(payload);
+    let body = text
+        .strip_prefix("print\n")
+        .ok_or_else(|| Q2ProtoError::MalformedResponse(text.clone()))?;
+
+    if body.trim() == "Bad rcon_password." {
+        return Err(Q2ProtoError::Rejected(
+            RejectReason::BadPassword,
+            body.trim().to_string(),
+        ));
+    }
+
+    Ok(body.to_string())
+}
+
+// one row of `rcon status`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RconSlot {
+    pub num: u32,
+    pub score: i32,
+    // None while the client is still connecting (CNCT) or a zombie (ZMBI).
+    pub ping: Option<u32>,
+    pub name: String,
+    pub lastmsg: u32,
+    pub address: String,
+    pub qport: u16,
+    // only r1q2 and q2pro print this one.
+    pub rate: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RconStatus {
+    pub map: Option<String>,
+    pub slots: Vec<RconSlot>,
+}
+
+pub fn parse_status(reply: &str) -> RconStatus {
+    let mut status = RconStatus::default();
+
+    for line in reply.lines() {
+        if let Some((key, val)) = line.split_once(':') {
+            if key.trim() == "map" {
+                status.map = Some(val.trim().to_string());
+                continue;
+            }
+        }
+
+        // the header and the dashes under it won't parse, neither will anything else.
+        if let Some(slot) = parse_slot(line) {
+            status.slots.push(slot);
+        }
+    }
+
+    status
+}
+
+// `num score ping name lastmsg address qport [rate ...]`. names can have spaces, so the
+// columns after the name are found from the address backwards, and the name is whatever
+// is between the ping's trailing space and lastmsg, minus the padding.
+fn parse_slot(line: &str) -> Option<RconSlot> {
+    let tokens = tokens_at(line);
+    if tokens.len() < 7 {
+        return None;
+    }
+
+    let address_at = tokens
+        .iter()
+        .rposition(|(_, t)| t.contains(':') || *t == "loopback")?;
+    if address_at < 5 || address_at + 1 >= tokens.len() {
+        return None;
+    }
+
+    let (ping_at, ping) = tokens[2];
+    let (lastmsg_at, lastmsg) = tokens[address_at - 1];
+    let name = line.get(ping_at + ping.len() + 1..lastmsg_at)?.trim_end();
+
+    Some(RconSlot {
+        num: tokens[0].1.parse().ok()?,
+        score: tokens[1].1.parse().ok()?,
+        ping: ping.parse().ok(),
+        name: name.to_string(),
+        lastmsg: lastmsg.parse().ok()?,
+        address: tokens[address_at].1.to_string(),
+        qport: tokens[address_at + 1].1.parse().ok()?,
+        rate: tokens.get(address_at + 2).and_then(|(_, r)| r.parse().ok()),
+    })
+}
+
+// the whitespace separated tokens, with where each one starts in the line.
+fn tokens_at(line: &str) -> Vec<(usize, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+
+    for (i, c) in line.char_indices() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some(i),
+            (true, Some(s)) => {
+                tokens.push((s, &line[s..i]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        tokens.push((s, &line[s..]));
+    }
+
+    tokens
+}
+
+// Info_Print pads keys out to this many columns and runs the value straight on after.
+const INFO_KEY_WIDTH: usize = 20;
+
+// Info_Print output, one pair per line. a key of INFO_KEY_WIDTH or more gets no padding, and
+// then there's nothing telling it apart from its value. we split those at the first space,
+// which is right for the ports that print one. a key with no value comes out as
+// `MISSING VALUE`, which we give back as an empty value.
+pub fn parse_info_print(reply: &str) -> HashMap<String, String> {
+    reply
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (key, val) = match line.get(..INFO_KEY_WIDTH) {
+                Some(key) if key.ends_with(' ') => (key.trim_end(), &line[INFO_KEY_WIDTH..]),
+                // the padding got trimmed off the end, so there's no value.
+                None if line.ends_with(' ') => (line.trim_end(), ""),
+                _ => match line.split_once(' ') {
+                    Some((key, val)) => (key, val.trim_start()),
+                    None => (line, ""),
+                },
+            };
+            let val = if val == "MISSING VALUE" { "" } else { val };
+            (key.to_string(), val.to_string())
+        })
+        .collect()
+}
+
+// `serverinfo` prints a header line, then the pairs.
+pub fn parse_serverinfo(reply: &str) -> HashMap<String, String> {
+    parse_info_print(reply.split_once('\n').map_or("", |(_, rest)| rest))
+}
+
+// `dumpuser` prints `userinfo` and a row of dashes, then the pairs.
+pub fn parse_dumpuser(reply: &str) -> Result<UserInfo, Q2ProtoError> {
+    let pairs = reply
+        .strip_prefix("userinfo\n")
+        .and_then(|rest| rest.split_once('\n'))
+        .map(|(_, pairs)| parse_info_print(pairs))
+        .ok_or_else(|| Q2ProtoError::MalformedResponse(reply.to_string()))?;
+
+    Ok(UserInfo { keys: pairs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_names_keep_their_spaces() {
+        let reply = "map              : q2dm1\n\
+num score ping name            lastmsg address               qport \n\
+--- ----- ---- --------------- ------- --------------------- ------\n\
+  0     5   48 a  b                  0 10.0.0.1:27901        31337\n\
+  1    -1 CNCT sixteen chars ok      3 10.0.0.2:27901          123\n\
+  2    12   30  lead                 1 loopback                  0 25000\n";
+
+        let status = parse_status(reply);
+        assert_eq!(status.map.as_deref(), Some("q2dm1"));
+
+        let names: Vec<&str> = status.slots.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["a  b", "sixteen chars ok", " lead"]);
+
+        assert_eq!(
+            status.slots[0],
+            RconSlot {
+                num: 0,
+                score: 5,
+                ping: Some(48),
+                name: "a  b".to_string(),
+                lastmsg: 0,
+                address: "10.0.0.1:27901".to_string(),
+                qport: 31337,
+                rate: None,
+            }
+        );
+        assert_eq!(status.slots[1].ping, None);
+        assert_eq!(status.slots[2].rate, Some(25000));
+    }
+
+    #[test]
+    fn serverinfo() {
+        let reply = "Server info settings:\n\
+hostname            my  server\n\
+maxclients          16\n\
+a_key_of_twenty_char 1\n\
+mapname             q2dm1\n\
+timelimit           \n";
+
+        let info = parse_serverinfo(reply);
+        assert_eq!(info.len(), 5);
+        assert_eq!(info["hostname"], "my  server");
+        assert_eq!(info["maxclients"], "16");
+        assert_eq!(info["a_key_of_twenty_char"], "1");
+        assert_eq!(info["mapname"], "q2dm1");
+        assert_eq!(info["timelimit"], "");
+    }
+
+    #[test]
+    fn dumpuser() {
+        let reply = "userinfo\n\
+--------\n\
+name                some player\n\
+skin                male/grunt\n\
+spectator           MISSING VALUE\n";
+
+        let info = parse_dumpuser(reply).unwrap();
+        assert_eq!(info.keys.len(), 3);
+        assert_eq!(info.keys["name"], "some player");
+        assert_eq!(info.keys["skin"], "male/grunt");
+        assert_eq!(info.keys["spectator"], "");
+
+        assert!(matches!(
+            parse_dumpuser("Userid 9 is not on the server\n"),
+            Err(Q2ProtoError::MalformedResponse(_))
+        ));
+    }
+}
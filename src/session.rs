@@ -0,0 +1,1012 @@
+use super::chat::parse_chat;
+use super::connection::{ClientTimeouts, ConnectionState};
+use super::error::{Q2ProtoError, RejectReason};
+use super::message::parse_op;
+use super::netchan::{NetChan, NetChanVanilla, RateLimit};
+use super::objects::{player_name, player_names, DeltaEntity, PrintLevel, ServerDataMessage};
+use super::subscription::{EventDispatcher, Subscription};
+use super::user_info::UserInfo;
+use super::{
+    Challenge, ClientEvent, ClientEventKind, ClientToServerOps, ProtocolVersion, OOB_PREFIX,
+};
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Cursor, Write};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+// keep the netchan alive even if we've got nothing to say.
+const NOP_INTERVAL: Duration = Duration::from_secs(2);
+
+// what we're trying to connect with. kept around so the handshake can be resent.
+struct PendingConnect {
+    proto: ProtocolVersion,
+    userinfo: UserInfo,
+    challenge: Option<Challenge>,
+}
+
+// The client side of the protocol without any sockets or clocks (sans-io). Feed it the
+// datagrams that came from the server along with the time they arrived, call
+// `handle_timeout` once `poll_timeout` says so, and send whatever `poll_transmit` hands out.
+// Q2ProtoClient and AsyncQ2ProtoClient are just this plus a socket.
+pub struct ClientSession {
+    qport: u16,
+    state: ConnectionState,
+    timeouts: ClientTimeouts,
+    pending_connect: Option<PendingConnect>,
+    chan: Box<NetChanVanilla>,
+    events: EventDispatcher,
+    event_sender: Option<Sender<ClientEvent>>,
+    emitted: VecDeque<ClientEvent>,
+    outgoing: VecDeque<Vec<u8>>,
+    version: String,
+    last_precache_value: u32,
+    serverdata: Option<ServerDataMessage>,
+    configstrings: HashMap<u16, Vec<u8>>,
+    baselines: HashMap<i16, DeltaEntity>,
+    // raw server messages for whoever's recording a demo. None when nobody is.
+    captured: Option<VecDeque<Vec<u8>>>,
+    last_msg_sent_time: Instant,
+    last_msg_recv_time: Instant,
+    last_resend_time: Instant,
+    resend_count: u32,
+    handshake_error: Option<Q2ProtoError>,
+}
+
+impl ClientSession {
+    // the qport goes in every netchan header, the version is what we answer `cmd version` with.
+    pub fn new(qport: u16, version: &str) -> ClientSession {
+        ClientSession {
+            qport,
+            state: ConnectionState::Disconnected,
+            timeouts: ClientTimeouts::default(),
+            pending_connect: None,
+            chan: Box::new(NetChanVanilla::new(true, qport)),
+            events: EventDispatcher::new(),
+            event_sender: None,
+            emitted: VecDeque::new(),
+            outgoing: VecDeque::new(),
+            version: version.to_string(),
+            last_precache_value: 0,
+            serverdata: None,
+            configstrings: HashMap::new(),
+            baselines: HashMap::new(),
+            captured: None,
+            last_msg_sent_time: Instant::now(),
+            last_msg_recv_time: Instant::now(),
+            last_resend_time: Instant::now(),
+            resend_count: 0,
+            handshake_error: None,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    // true while there's anything to pump for.
+    pub fn is_live(&self) -> bool {
+        self.state.has_netchan() || self.state.is_handshaking()
+    }
+
+    pub fn timeouts(&self) -> ClientTimeouts {
+        self.timeouts
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: ClientTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn set_rate_limit(&mut self, rate: RateLimit) {
+        self.chan.set_rate_limit(rate);
+    }
+
+    pub fn rate_limit(&self) -> RateLimit {
+        self.chan.rate_limit()
+    }
+
+    // the next datagram that should go out to the server.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.outgoing.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<ClientEvent> {
+        self.emitted.pop_front()
+    }
+
+    // every event that hasn't been polled yet.
+    pub fn take_events(&mut self) -> Vec<ClientEvent> {
+        self.emitted.drain(..).collect()
+    }
+
+    // when `handle_timeout` needs to run next, if there's anything to wait for.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        if self.state.is_handshaking() {
+            return Some(self.last_resend_time + self.timeouts.retry_interval);
+        }
+
+        if !self.state.has_netchan() {
+            return None;
+        }
+
+        let mut next = (self.last_msg_recv_time + self.timeouts.timeout)
+            .min(self.last_msg_sent_time + NOP_INTERVAL);
+
+        if self.chan.should_transmit() {
+            next = next.min(
+                self.chan
+                    .next_packet_time()
+                    .unwrap_or(self.last_msg_sent_time),
+            );
+        }
+
+        Some(next)
+    }
+
+    fn queue_oob(&mut self, msg: &[u8]) {
+        self.outgoing.push_back(oob_packet(msg));
+    }
+
+    pub fn send_command(&mut self, cmd: &str) -> Result<(), Q2ProtoError> {
+        if !self.state.has_netchan() {
+            return Err(Q2ProtoError::NotConnected);
+        }
+
+        self.chan
+            .message
+            .cur
+            .write_u8(ClientToServerOps::StringCmd as u8)?;
+        self.chan
+            .message
+            .write_string(cmd)
+            .ok_or(Q2ProtoError::Overflow)
+    }
+
+    // do the whole process to get into a server, starting with getchallenge.
+    pub fn start_challenge(
+        &mut self,
+        proto: ProtocolVersion,
+        userinfo: UserInfo,
+        now: Instant,
+    ) -> Result<(), Q2ProtoError> {
+        self.begin_handshake(proto, userinfo, now)?;
+        self.state = ConnectionState::Challenging;
+        self.queue_oob(b"getchallenge");
+
+        Ok(())
+    }
+
+    // skip getchallenge, we already have one.
+    pub fn start_connect(
+        &mut self,
+        challenge: Challenge,
+        proto: ProtocolVersion,
+        userinfo: UserInfo,
+        now: Instant,
+    ) -> Result<(), Q2ProtoError> {
+        self.begin_handshake(proto, userinfo, now)?;
+        if let Some(pending) = self.pending_connect.as_mut() {
+            pending.challenge = Some(challenge);
+        }
+        self.state = ConnectionState::Connecting;
+        self.send_connect(now);
+
+        Ok(())
+    }
+
+    fn begin_handshake(
+        &mut self,
+        proto: ProtocolVersion,
+        userinfo: UserInfo,
+        now: Instant,
+    ) -> Result<(), Q2ProtoError> {
+        // woops it takes more work than this to get r1q2 and q2pro support!
+        if proto != ProtocolVersion::Vanilla {
+            return Err(Q2ProtoError::UnsupportedProtocol(proto));
+        }
+
+        // behave like a real client would with these settings.
+        self.chan
+            .set_rate_limit(RateLimit::from_userinfo(&userinfo));
+
+        self.pending_connect = Some(PendingConnect {
+            proto,
+            userinfo,
+            challenge: None,
+        });
+        self.resend_count = 0;
+        self.last_resend_time = now;
+        self.last_msg_recv_time = now;
+        self.last_msg_sent_time = now;
+        self.handshake_error = None;
+
+        Ok(())
+    }
+
+    fn send_connect(&mut self, now: Instant) -> Option<()> {
+        let pending = self.pending_connect.as_ref()?;
+        let challenge = pending.challenge.as_ref()?;
+
+        // send the connect message
+        let msg = format!(
+            "connect {} {} {} \"{}\"\n",
+            pending.proto as u8,
+            self.qport,
+            challenge.ch_value,
+            pending.userinfo.as_string()
+        );
+
+        self.queue_oob(msg.as_ref());
+        self.last_resend_time = now;
+
+        Some(())
+    }
+
+    // a datagram from the server, connectionless or not.
+    pub fn handle_datagram(&mut self, data: &[u8], now: Instant) -> Result<(), Q2ProtoError> {
+        self.last_msg_recv_time = now;
+
+        if let Some(payload) = connectionless_payload(data) {
+            self.handle_connectionless(payload, now);
+        } else if self.state.has_netchan() {
+            let mut cur = Cursor::new(data);
+            if self.chan.process(&mut cur) {
+                // what's left after the netchan header is what goes in a demo.
+                if let Some(captured) = self.captured.as_mut() {
+                    captured.push_back(data[cur.position() as usize..].to_vec());
+                }
+
+                self.parse_command(&mut cur, now)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_command<T: AsRef<[u8]>>(
+        &mut self,
+        cursor: &mut Cursor<T>,
+        now: Instant,
+    ) -> Result<(), Q2ProtoError> {
+        while let Ok(cmd_byte) = cursor.read_u8() {
+            let evt = match parse_op(cmd_byte, cursor)? {
+                Some(evt) => evt,
+                None => continue,
+            };
+
+            match &evt {
+                ClientEvent::Disconnect => {
+                    self.drop_connection(now);
+                }
+                ClientEvent::Reconnect => {
+                    self.restart_handshake(now);
+                }
+                // If we receive a \177c (7f6c -- a short) we need to reply with a command
+                // containing whatever value it requested of us.
+                ClientEvent::StuffText(str) if !self.check_stuffcmd(str, now) => continue,
+                ClientEvent::ServerData(serverdata) => {
+                    // cl_clearstate: whatever we knew about the last level is stale now.
+                    self.clear_level_state();
+                    self.serverdata = Some(serverdata.clone());
+                    self.state = ConnectionState::Loading;
+                }
+                ClientEvent::ConfigString(index, value) => {
+                    self.configstrings.insert(*index, value.clone());
+                }
+                ClientEvent::DeltaEntity(ent) => {
+                    // baselines are the only loose entities, the rest come in frames.
+                    self.baselines.insert(ent.number(), ent.clone());
+                }
+                _ => {}
+            }
+
+            let chat = match &evt {
+                ClientEvent::Print(PrintLevel::CHAT, text) => {
+                    parse_chat(text, &player_names(&self.configstrings))
+                }
+                _ => None,
+            };
+
+            self.emit(evt);
+            if let Some(chat) = chat {
+                self.emit(ClientEvent::Chat(chat));
+            }
+        }
+
+        Ok(())
+    }
+
+    // listeners first, then the channel, then it waits for whoever calls pump next.
+    fn emit(&mut self, evt: ClientEvent) {
+        self.events.dispatch(&evt);
+
+        if let Some(sender) = &self.event_sender {
+            if sender.send(evt.clone()).is_err() {
+                // nobody's listening on the other end anymore.
+                self.event_sender = None;
+            }
+        }
+
+        self.emitted.push_back(evt);
+    }
+
+    // answers to getchallenge/connect while we're handshaking. anything else is ignored.
+    fn handle_connectionless(&mut self, data: &[u8], now: Instant) {
+        let str = String::from_utf8_lossy(data);
+
+        // the server turned our connect down. before that a print is just somebody's stray
+        // status reply.
+        if self.state == ConnectionState::Connecting && str.starts_with("print\n") {
+            let msg = str["print\n".len()..].trim().to_string();
+            self.fail_handshake(Q2ProtoError::Rejected(
+                RejectReason::from_message(&msg),
+                msg,
+            ));
+            return;
+        }
+
+        match self.state {
+            ConnectionState::Challenging => {
+                if let Ok(ch) = parse_challenge(&str) {
+                    if let Some(pending) = self.pending_connect.as_mut() {
+                        pending.challenge = Some(ch);
+                    }
+
+                    self.state = ConnectionState::Connecting;
+                    self.resend_count = 0;
+                    self.send_connect(now);
+                }
+            }
+            ConnectionState::Connecting => {
+                if !str.starts_with("client_connect") {
+                    return;
+                }
+
+                match parse_client_connect(&str) {
+                    Ok(()) => self.establish_netchan(now),
+                    Err(e) => self.fail_handshake(e),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn fail_handshake(&mut self, err: Q2ProtoError) {
+        self.state = ConnectionState::Disconnected;
+        self.pending_connect = None;
+        self.handshake_error = Some(err);
+    }
+
+    // why the last handshake didn't get us in, if it didn't.
+    pub fn take_handshake_error(&mut self) -> Option<Q2ProtoError> {
+        self.handshake_error.take()
+    }
+
+    fn establish_netchan(&mut self, now: Instant) {
+        let rate = self.chan.rate_limit();
+        *self.chan = NetChanVanilla::new(true, self.qport);
+        self.chan.set_rate_limit(rate);
+
+        self.state = ConnectionState::Connected; // we did it! we're considered to be 'connected'.
+        self.last_msg_recv_time = now;
+        self.last_msg_sent_time = now;
+
+        let _ = self.send_command("new");
+    }
+
+    // svc_reconnect: the server went away and wants us back. get a fresh challenge
+    // and connect again with the same userinfo, like cl_checkforresend does.
+    fn restart_handshake(&mut self, now: Instant) {
+        self.clear_level_state();
+
+        if self.pending_connect.is_none() {
+            self.state = ConnectionState::Zombie;
+            return;
+        }
+
+        self.state = ConnectionState::Challenging;
+        self.resend_count = 0;
+        self.last_resend_time = now;
+        self.queue_oob(b"getchallenge");
+    }
+
+    fn clear_level_state(&mut self) {
+        self.serverdata = None;
+        self.configstrings.clear();
+        self.baselines.clear();
+    }
+
+    pub fn configstring(&self, index: u16) -> Option<&[u8]> {
+        self.configstrings.get(&index).map(|f| f.as_slice())
+    }
+
+    pub fn configstrings(&self) -> &HashMap<u16, Vec<u8>> {
+        &self.configstrings
+    }
+
+    pub fn baseline(&self, number: i16) -> Option<&DeltaEntity> {
+        self.baselines.get(&number)
+    }
+
+    pub fn baselines(&self) -> &HashMap<i16, DeltaEntity> {
+        &self.baselines
+    }
+
+    // the serverdata for the level we're on.
+    pub fn serverdata(&self) -> Option<&ServerDataMessage> {
+        self.serverdata.as_ref()
+    }
+
+    // our name as the game has it, from our playerskin configstring. until that's in, the
+    // one we asked for in the userinfo.
+    pub fn name(&self) -> String {
+        if let Some(name) = self
+            .serverdata
+            .as_ref()
+            .and_then(|sd| player_name(&self.configstrings, sd.clnum))
+        {
+            return name;
+        }
+
+        self.pending_connect
+            .as_ref()
+            .and_then(|pending| pending.userinfo.keys.get("name"))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // keep a copy of every server message for poll_message. turning it off drops the backlog.
+    pub fn set_capture_messages(&mut self, capture: bool) {
+        if !capture {
+            self.captured = None;
+        } else if self.captured.is_none() {
+            self.captured = Some(VecDeque::new());
+        }
+    }
+
+    // the next server message as it came in, minus the netchan header.
+    pub fn poll_message(&mut self) -> Option<Vec<u8>> {
+        self.captured.as_mut()?.pop_front()
+    }
+
+    // tell the server we're leaving and forget about the connection.
+    pub fn disconnect(&mut self, now: Instant) {
+        if self.send_command("disconnect").is_ok() {
+            self.transmit(now);
+        }
+
+        self.state = ConnectionState::Disconnected;
+        self.pending_connect = None;
+    }
+
+    pub fn subscribe(
+        &mut self,
+        kind: Option<ClientEventKind>,
+        callback: Box<dyn FnMut(&ClientEvent) + Send>,
+    ) -> Subscription {
+        self.events.subscribe(kind, callback)
+    }
+
+    pub fn set_event_sender(&mut self, sender: Option<Sender<ClientEvent>>) {
+        self.event_sender = sender;
+    }
+
+    // resends, timeouts, keepalives and whatever the netchan has pending.
+    // calling it more often than poll_timeout asks for is harmless.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        if self.state.is_handshaking() {
+            self.check_for_resend(now);
+            return;
+        }
+
+        if !self.state.has_netchan() {
+            return;
+        }
+
+        if now.duration_since(self.last_msg_recv_time) > self.timeouts.timeout {
+            self.timed_out();
+            return;
+        }
+
+        let should_nop = now.duration_since(self.last_msg_sent_time) > NOP_INTERVAL;
+
+        if should_nop {
+            self.send_nop();
+            self.last_msg_sent_time = now;
+        }
+
+        if self.chan.should_transmit() && self.chan.can_packet(now) {
+            self.transmit(now);
+        }
+    }
+
+    fn transmit(&mut self, now: Instant) {
+        let data = [0u8; 0];
+        let transmit_cursor = self.chan.transmit(&data, now);
+        let transmit_data_size = transmit_cursor.position() as usize;
+        let transmit_data = &transmit_cursor.into_inner()[..transmit_data_size];
+
+        self.outgoing.push_back(transmit_data.to_vec());
+        self.last_msg_sent_time = now;
+    }
+
+    // CL_CheckForResend: the challenge or connect got lost, so send it again.
+    fn check_for_resend(&mut self, now: Instant) {
+        if now.duration_since(self.last_resend_time) < self.timeouts.retry_interval {
+            return;
+        }
+
+        if self.resend_count >= self.timeouts.max_retries {
+            self.timed_out();
+            return;
+        }
+
+        self.resend_count += 1;
+        self.last_resend_time = now;
+
+        if self.state == ConnectionState::Challenging {
+            self.queue_oob(b"getchallenge");
+        } else {
+            self.send_connect(now);
+        }
+    }
+
+    fn timed_out(&mut self) {
+        if self.state.has_netchan() {
+            self.state = ConnectionState::Zombie;
+            self.pending_connect = None;
+        } else {
+            self.fail_handshake(Q2ProtoError::Timeout);
+        }
+
+        self.emit(ClientEvent::Timeout);
+    }
+
+    // the server's done with us. let it know we got the message.
+    fn drop_connection(&mut self, now: Instant) {
+        if self.send_command("disconnect").is_ok() {
+            self.transmit(now);
+        }
+
+        self.state = ConnectionState::Zombie;
+        self.pending_connect = None;
+    }
+
+    fn send_nop(&mut self) -> Option<()> {
+        self.chan
+            .message
+            .cur
+            .write_u8(ClientToServerOps::Nop as u8)
+            .ok()
+    }
+
+    fn check_stuffcmd(&mut self, stuff_text: &[u8], now: Instant) -> bool {
+        let cmd_list = stuff_text.split(|f| *f == b'\n');
+
+        for cmd in cmd_list {
+            let stuffcmd_head = b"cmd \x7fc";
+            let bytes: &[u8] = cmd;
+
+            // Let the protocol (us) handle it.
+            // The way Q2 does is by actually expanding the variables but we do the minimum work possible.
+            if bytes.starts_with(stuffcmd_head) {
+                let cmd_slice = &bytes[7..];
+                let cmd_str_opt = String::from_utf8(cmd_slice.to_vec());
+                if cmd_str_opt.is_err() {
+                    return false;
+                }
+
+                let cmd_str = cmd_str_opt.unwrap();
+                if cmd_str.starts_with("version") {
+                    self.send_result_command(format!("version \"{}\"", &self.version).as_ref());
+                } else if cmd_str.starts_with("actoken") {
+                    self.send_result_command("actoken");
+                }
+            }
+
+            let changing_cmd = b"changing";
+            let precache_cmd = b"precache";
+            let reconnect_cmd = b"reconnect";
+
+            if bytes.starts_with(precache_cmd) {
+                // cmd_precache_f
+                // throw an event that requests a precache?
+                self.last_precache_value = String::from_utf8_lossy(bytes.get(9..).unwrap_or(&[]))
+                    .trim()
+                    .parse()
+                    .unwrap_or(0);
+
+                let msg = format!("begin {}", self.last_precache_value);
+                let _ = self.send_command(msg.as_ref());
+                self.state = ConnectionState::Active;
+
+                self.last_msg_sent_time = now;
+            } else if bytes.starts_with(changing_cmd) {
+                // cmd_changing_f: the level's going away. we're not active anymore, but not disconnected.
+                // the new level's configstrings and baselines come in after serverdata.
+                self.clear_level_state();
+                self.state = ConnectionState::Connected;
+            } else if bytes.starts_with(reconnect_cmd) {
+                // cl_reconnect_f: the server restarted the level on the same netchan, so just ask again.
+                self.clear_level_state();
+                if self.send_command("new").is_ok() {
+                    self.state = ConnectionState::Connected;
+                }
+            }
+        }
+
+        true // Pass it to the client
+    }
+
+    fn send_result_command(&mut self, cmd: &str) -> Option<()> {
+        if !self.state.has_netchan() {
+            return None;
+        }
+
+        self.chan
+            .message
+            .cur
+            .write_u8(ClientToServerOps::StringCmd as u8)
+            .ok()?;
+        self.chan.message.cur.write_all(b"\x7fc ").ok()?;
+        self.chan.message.write_string(cmd)?;
+
+        Some(())
+    }
+}
+
+pub fn oob_packet(msg: &[u8]) -> Vec<u8> {
+    let mut send = Vec::with_capacity(4 + msg.len());
+    send.extend_from_slice(OOB_PREFIX.as_slice());
+    send.extend_from_slice(msg);
+    send
+}
+
+// the part after the 0xffffffff, if it's a connectionless packet at all.
+pub fn connectionless_payload(data: &[u8]) -> Option<&[u8]> {
+    if data.len() < 4 || data[..4] != OOB_PREFIX {
+        return None;
+    }
+
+    Some(&data[4..])
+}
+
+pub fn parse_challenge(str: &str) -> Result<Challenge, Q2ProtoError> {
+    let malformed = || Q2ProtoError::MalformedResponse(str.to_string());
+
+    let mut split_pat = str.split(' ');
+    if split_pat.next() != Some("challenge") {
+        return Err(malformed());
+    };
+
+    let ch_value: &str = split_pat.next().ok_or_else(malformed)?;
+    let protos: &str = split_pat.next().ok_or_else(malformed)?.trim_end();
+
+    if !protos.starts_with("p=") {
+        return Err(malformed());
+    }
+
+    Ok(Challenge {
+        ch_value: String::from(ch_value),
+        protocols: String::from(&protos[2..]),
+    })
+}
+
+fn parse_client_connect(data: &str) -> Result<(), Q2ProtoError> {
+    let mut response = data.split(' ');
+
+    if response.next().map(|f| f.trim_end()) != Some("client_connect") {
+        return Err(Q2ProtoError::MalformedResponse(data.to_string()));
+    }
+
+    for re in response {
+        if re.starts_with("ac=") {
+            // anticheat, which we can't do
+            return Err(Q2ProtoError::Rejected(
+                RejectReason::AnticheatRequired,
+                data.trim().to_string(),
+            ));
+        }
+        // else if re.starts_with("map=") { // map
+        // } else if re.starts_with("nc=") { // netchan
+        // }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::connection::DEFAULT_TIMEOUT;
+    use crate::message::write_event;
+    use crate::objects::{ProtocolInfo, CS_NAME, CS_PLAYERSKINS};
+
+    const QPORT: u16 = 1234;
+
+    fn transmitted(session: &mut ClientSession) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| session.poll_transmit()).collect()
+    }
+
+    fn challenging(now: Instant) -> ClientSession {
+        let mut session = ClientSession::new(QPORT, "test");
+        session
+            .start_challenge(ProtocolVersion::Vanilla, UserInfo::new(), now)
+            .unwrap();
+        session
+    }
+
+    // through the handshake, with the netchan up and `new` already sent.
+    pub(crate) fn connected(now: Instant) -> ClientSession {
+        let mut session = challenging(now);
+        session
+            .handle_datagram(&oob_packet(b"challenge 5 p=34"), now)
+            .unwrap();
+        session
+            .handle_datagram(&oob_packet(b"client_connect"), now)
+            .unwrap();
+        transmitted(&mut session);
+        session
+    }
+
+    // a netchan packet from the server with these in it.
+    pub(crate) fn server_packet(seq: u32, events: &[ClientEvent]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&seq.to_le_bytes());
+        packet.extend_from_slice(&0u32.to_le_bytes());
+        for evt in events {
+            write_event(&mut packet, evt);
+        }
+        packet
+    }
+
+    pub(crate) fn serverdata() -> ServerDataMessage {
+        ServerDataMessage {
+            protocol: ProtocolVersion::Vanilla as u32,
+            srv_count: 1,
+            attract_loop: 0,
+            gamedir: "baseq2".to_string(),
+            clnum: 3,
+            levelname: "The Edge".to_string(),
+            protocol_info: ProtocolInfo::Vanilla,
+        }
+    }
+
+    #[test]
+    fn handshake() {
+        let now = Instant::now();
+        let mut session = challenging(now);
+        assert_eq!(session.state(), ConnectionState::Challenging);
+        assert_eq!(transmitted(&mut session), [oob_packet(b"getchallenge")]);
+
+        session
+            .handle_datagram(&oob_packet(b"challenge 5 p=34"), now)
+            .unwrap();
+        assert_eq!(session.state(), ConnectionState::Connecting);
+        let sent = transmitted(&mut session);
+        assert_eq!(sent.len(), 1);
+        let connect = connectionless_payload(&sent[0]).unwrap();
+        assert!(connect.starts_with(b"connect 34 1234 5 \""));
+
+        session
+            .handle_datagram(&oob_packet(b"client_connect"), now)
+            .unwrap();
+        assert_eq!(session.state(), ConnectionState::Connected);
+
+        // `new` goes out over the netchan.
+        session.handle_timeout(now);
+        let sent = transmitted(&mut session);
+        assert_eq!(sent.len(), 1);
+        assert!(connectionless_payload(&sent[0]).is_none());
+        let new = [&[ClientToServerOps::StringCmd as u8][..], b"new\0"].concat();
+        assert!(sent[0].windows(new.len()).any(|w| w == new));
+    }
+
+    #[test]
+    fn resends_then_gives_up() {
+        let now = Instant::now();
+        let mut session = challenging(now);
+        transmitted(&mut session);
+
+        // nothing to resend yet.
+        session.handle_timeout(now + Duration::from_secs(1));
+        assert!(transmitted(&mut session).is_empty());
+
+        let retry = session.timeouts().retry_interval;
+        let mut at = now;
+        for _ in 0..session.timeouts().max_retries {
+            at += retry;
+            assert_eq!(session.poll_timeout(), Some(at));
+            session.handle_timeout(at);
+            assert_eq!(transmitted(&mut session), [oob_packet(b"getchallenge")]);
+        }
+
+        session.handle_timeout(at + retry);
+        assert!(transmitted(&mut session).is_empty());
+        assert_eq!(session.state(), ConnectionState::Disconnected);
+        assert!(matches!(session.poll_event(), Some(ClientEvent::Timeout)));
+        assert!(matches!(
+            session.take_handshake_error(),
+            Some(Q2ProtoError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn server_goes_quiet() {
+        let now = Instant::now();
+        let mut session = challenging(now);
+        session
+            .handle_datagram(&oob_packet(b"challenge 5 p=34"), now)
+            .unwrap();
+        session
+            .handle_datagram(&oob_packet(b"client_connect"), now)
+            .unwrap();
+
+        // the nops keep going out, but nothing comes back.
+        session.handle_timeout(now + DEFAULT_TIMEOUT / 2);
+        assert!(session.poll_event().is_none());
+
+        session.handle_timeout(now + DEFAULT_TIMEOUT + Duration::from_secs(1));
+        assert_eq!(session.state(), ConnectionState::Zombie);
+        assert!(matches!(session.poll_event(), Some(ClientEvent::Timeout)));
+        assert_eq!(session.poll_timeout(), None);
+    }
+
+    #[test]
+    fn rejected() {
+        let now = Instant::now();
+        let mut session = challenging(now);
+
+        // we haven't asked to connect yet, so it can't be a no.
+        session
+            .handle_datagram(&oob_packet(b"print\nServer is full.\n"), now)
+            .unwrap();
+        assert_eq!(session.state(), ConnectionState::Challenging);
+        assert!(session.take_handshake_error().is_none());
+
+        session
+            .handle_datagram(&oob_packet(b"challenge 5 p=34"), now)
+            .unwrap();
+        session
+            .handle_datagram(&oob_packet(b"print\nServer is full.\n"), now)
+            .unwrap();
+        assert_eq!(session.state(), ConnectionState::Disconnected);
+        assert!(matches!(
+            session.take_handshake_error(),
+            Some(Q2ProtoError::Rejected(RejectReason::ServerFull, msg)) if msg == "Server is full."
+        ));
+    }
+
+    #[test]
+    fn rejected_password() {
+        let now = Instant::now();
+        let mut session = challenging(now);
+        session
+            .handle_datagram(&oob_packet(b"challenge 5 p=34"), now)
+            .unwrap();
+
+        session
+            .handle_datagram(&oob_packet(b"print\nBad password.\n"), now)
+            .unwrap();
+        assert!(matches!(
+            session.take_handshake_error(),
+            Some(Q2ProtoError::Rejected(RejectReason::BadPassword, _))
+        ));
+    }
+
+    // whether `cmd` is waiting to go out as a string command, and forget what's waiting.
+    fn take_command(session: &mut ClientSession, cmd: &[u8]) -> bool {
+        let cmd = [&[ClientToServerOps::StringCmd as u8][..], cmd, b"\0"].concat();
+        let queued = session.chan.message.get_msg();
+        session.chan.message.clear().unwrap();
+        queued.windows(cmd.len()).any(|w| w == cmd)
+    }
+
+    // serverdata, a configstring and a baseline, like at the start of every level.
+    fn level() -> Vec<ClientEvent> {
+        vec![
+            ClientEvent::ServerData(serverdata()),
+            ClientEvent::ConfigString(CS_NAME, b"The Edge".to_vec()),
+            ClientEvent::DeltaEntity(DeltaEntity {
+                number: 1,
+                model_index: Some(1),
+                ..Default::default()
+            }),
+        ]
+    }
+
+    fn has_level(session: &ClientSession) -> bool {
+        session.serverdata().is_some()
+            && session.configstring(CS_NAME).is_some()
+            && session.baseline(1).is_some()
+    }
+
+    #[test]
+    fn level_changes() {
+        let now = Instant::now();
+        let mut session = connected(now);
+        assert!(take_command(&mut session, b"new"));
+
+        let mut events = level();
+        events.push(ClientEvent::StuffText(b"precache 7\n".to_vec()));
+        session
+            .handle_datagram(&server_packet(1, &events), now)
+            .unwrap();
+        assert!(has_level(&session));
+        assert!(take_command(&mut session, b"begin 7"));
+        assert_eq!(session.state(), ConnectionState::Active);
+
+        // the level's going away, the new one comes in with the next serverdata.
+        let changing = ClientEvent::StuffText(b"changing\n".to_vec());
+        session
+            .handle_datagram(&server_packet(2, &[changing]), now)
+            .unwrap();
+        assert!(!has_level(&session));
+        assert_eq!(session.state(), ConnectionState::Connected);
+
+        // same netchan, so we just ask for the level again.
+        let mut events = level();
+        events.push(ClientEvent::StuffText(b"reconnect\n".to_vec()));
+        session
+            .handle_datagram(&server_packet(3, &events), now)
+            .unwrap();
+        assert!(!has_level(&session));
+        assert!(take_command(&mut session, b"new"));
+        assert_eq!(session.state(), ConnectionState::Connected);
+    }
+
+    #[test]
+    fn svc_reconnect() {
+        let now = Instant::now();
+        let mut session = connected(now);
+        session
+            .handle_datagram(&server_packet(1, &level()), now)
+            .unwrap();
+        assert!(has_level(&session));
+        assert_eq!(session.state(), ConnectionState::Loading);
+
+        // the server restarted, so it's a whole new handshake with the same userinfo.
+        let later = now + Duration::from_secs(1);
+        session
+            .handle_datagram(&server_packet(2, &[ClientEvent::Reconnect]), later)
+            .unwrap();
+        assert!(!has_level(&session));
+        assert_eq!(session.state(), ConnectionState::Challenging);
+        assert!(transmitted(&mut session).contains(&oob_packet(b"getchallenge")));
+
+        session
+            .handle_datagram(&oob_packet(b"challenge 6 p=34"), later)
+            .unwrap();
+        assert_eq!(session.state(), ConnectionState::Connecting);
+    }
+
+    #[test]
+    fn name_follows_the_playerskin() {
+        let now = Instant::now();
+        let mut session = ClientSession::new(QPORT, "test");
+        let mut userinfo = UserInfo::new();
+        userinfo
+            .keys
+            .insert("name".to_string(), "asked".to_string());
+        session
+            .start_challenge(ProtocolVersion::Vanilla, userinfo, now)
+            .unwrap();
+        assert_eq!(session.name(), "asked");
+
+        session
+            .handle_datagram(&oob_packet(b"challenge 5 p=34"), now)
+            .unwrap();
+        session
+            .handle_datagram(&oob_packet(b"client_connect"), now)
+            .unwrap();
+        // serverdata says we're in slot 3.
+        let skin = ClientEvent::ConfigString(CS_PLAYERSKINS + 3, b"given\\male/grunt".to_vec());
+        session
+            .handle_datagram(
+                &server_packet(1, &[ClientEvent::ServerData(serverdata()), skin]),
+                now,
+            )
+            .unwrap();
+        assert_eq!(session.name(), "given");
+    }
+}
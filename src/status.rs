@@ -0,0 +1,202 @@
+use super::error::{Q2ProtoError, RejectReason};
+use super::text::decode;
+use super::user_info::UserInfo;
+use std::collections::HashMap;
+
+// one line of the player list at the end of a status reply.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlayerStatus {
+    pub score: i32,
+    pub ping: i32,
+    pub name: String,
+}
+
+// what the server answers to an OOB `status`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerStatus {
+    // the serverinfo cvars: hostname, mapname, maxclients, gamename, version...
+    pub info: HashMap<String, String>,
+    pub players: Vec<PlayerStatus>,
+}
+
+impl ServerStatus {
+    // takes the connectionless payload, `print\n` and all.
+    pub fn parse(payload: &[u8]) -> Result<ServerStatus, Q2ProtoError> {
+        let malformed = || Q2ProtoError::MalformedResponse(decode(payload));
+
+        let body = payload.strip_prefix(b"print\n").ok_or_else(malformed)?;
+        let mut lines = body.split(|b| *b == b'\n');
+
+        let info = lines.next().map(decode).unwrap_or_default();
+        if !info.starts_with('\\') {
+            return Err(malformed());
+        }
+
+        // anything that isn't a player line gets skipped, some mods append their own stuff.
+        let players = lines.filter_map(|l| parse_player(&decode(l))).collect();
+
+        Ok(ServerStatus {
+            info: UserInfo::from_string(&info).keys,
+            players,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.info.get(key).map(String::as_str)
+    }
+
+    pub fn hostname(&self) -> Option<&str> {
+        self.get("hostname")
+    }
+
+    pub fn map_name(&self) -> Option<&str> {
+        self.get("mapname")
+    }
+
+    pub fn max_clients(&self) -> Option<u32> {
+        self.get("maxclients")?.trim().parse().ok()
+    }
+
+    // mods set `gamename`, the engine sets `game` when it's not baseq2.
+    pub fn game(&self) -> Option<&str> {
+        self.get("gamename").or_else(|| self.get("game"))
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.get("version")
+    }
+}
+
+// what the server answers to an OOB `info <protocol>`. the short version of status,
+// what the in-game server browser shows.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub hostname: String,
+    pub map_name: String,
+    pub players: u32,
+    pub max_players: u32,
+}
+
+impl ServerInfo {
+    // takes the connectionless payload, `info\n` and all.
+    pub fn parse(payload: &[u8]) -> Result<ServerInfo, Q2ProtoError> {
+        let text = decode(payload);
+        let malformed = || Q2ProtoError::MalformedResponse(text.clone());
+
+        let line = text.strip_prefix("info\n").ok_or_else(malformed)?.trim();
+
+        // we asked with a protocol it doesn't speak.
+        if line.ends_with("wrong version") {
+            return Err(Q2ProtoError::Rejected(
+                RejectReason::VersionMismatch,
+                line.to_string(),
+            ));
+        }
+
+        // "%16s %8s %2i/%2i", so the hostname can have spaces but the map can't.
+        let (rest, max_players) = line.rsplit_once('/').ok_or_else(malformed)?;
+        let (rest, players) = rest.trim_end().rsplit_once(' ').ok_or_else(malformed)?;
+        let (hostname, map_name) = rest.trim_end().rsplit_once(' ').ok_or_else(malformed)?;
+
+        Ok(ServerInfo {
+            hostname: hostname.trim().to_string(),
+            map_name: map_name.to_string(),
+            players: players.parse().map_err(|_| malformed())?,
+            max_players: max_players.trim().parse().map_err(|_| malformed())?,
+        })
+    }
+}
+
+// `score ping "name"`
+fn parse_player(line: &str) -> Option<PlayerStatus> {
+    let (score, rest) = line.trim().split_once(' ')?;
+    let (ping, name) = rest.trim_start().split_once(' ')?;
+    let name = name.trim();
+    let name = name
+        .strip_prefix('"')
+        .and_then(|n| n.strip_suffix('"'))
+        .unwrap_or(name);
+
+    Some(PlayerStatus {
+        score: score.parse().ok()?,
+        ping: ping.parse().ok()?,
+        name: name.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_with_players() {
+        let reply = b"print\n\\hostname\\my server\\mapname\\q2dm1\\maxclients\\8\n\
+5 48 \"a player\"\n\
+-1 999 \"lag  monster\"\n";
+
+        let status = ServerStatus::parse(reply).unwrap();
+        assert_eq!(status.hostname(), Some("my server"));
+        assert_eq!(status.map_name(), Some("q2dm1"));
+        assert_eq!(status.max_clients(), Some(8));
+        assert_eq!(
+            status.players,
+            [
+                PlayerStatus {
+                    score: 5,
+                    ping: 48,
+                    name: "a player".to_string(),
+                },
+                PlayerStatus {
+                    score: -1,
+                    ping: 999,
+                    name: "lag  monster".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_status() {
+        let status = ServerStatus::parse(b"print\n\\hostname\\nobody home\n").unwrap();
+        assert_eq!(status.hostname(), Some("nobody home"));
+        assert!(status.players.is_empty());
+
+        assert!(matches!(
+            ServerStatus::parse(b"print\nno info here\n"),
+            Err(Q2ProtoError::MalformedResponse(_))
+        ));
+    }
+
+    #[test]
+    fn info() {
+        let info = ServerInfo::parse(b"info\n       my server    q2dm1  3/16\n").unwrap();
+        assert_eq!(
+            info,
+            ServerInfo {
+                hostname: "my server".to_string(),
+                map_name: "q2dm1".to_string(),
+                players: 3,
+                max_players: 16,
+            }
+        );
+
+        // %16s doesn't cut anything off, it only pads.
+        let info =
+            ServerInfo::parse(b"info\nthe longest server name around q2dm1 12/32\n").unwrap();
+        assert_eq!(info.hostname, "the longest server name around");
+        assert_eq!(info.map_name, "q2dm1");
+        assert_eq!((info.players, info.max_players), (12, 32));
+    }
+
+    #[test]
+    fn info_wrong_version() {
+        assert!(matches!(
+            ServerInfo::parse(b"info\nmy server: wrong version\n"),
+            Err(Q2ProtoError::Rejected(RejectReason::VersionMismatch, _))
+        ));
+        assert!(matches!(
+            ServerInfo::parse(b"info\nmy server\n"),
+            Err(Q2ProtoError::MalformedResponse(_))
+        ));
+    }
+}
@@ -0,0 +1,169 @@
+use super::{ClientEvent, ClientEventKind};
+use std::mem;
+use std::sync::{Arc, Mutex, Weak};
+
+pub type ClientEventListener = Box<dyn FnMut(&ClientEvent) + Send>;
+
+struct Listener {
+    id: u64,
+    filter: Option<ClientEventKind>, // None gets everything
+    callback: ClientEventListener,
+}
+
+#[derive(Default)]
+struct ListenerRegistry {
+    next_id: u64,
+    listeners: Vec<Listener>,
+
+    // listeners are taken out of the registry while they run, so a callback can
+    // subscribe or drop a handle without deadlocking. these get sorted out afterwards.
+    dispatching: bool,
+    removed: Vec<u64>,
+}
+
+// Keeps a listener registered. Dropping it unsubscribes.
+#[must_use = "dropping a Subscription unsubscribes the listener right away"]
+pub struct Subscription {
+    registry: Weak<Mutex<ListenerRegistry>>,
+    id: u64,
+}
+
+impl Subscription {
+    // keep the listener around for as long as the client lives.
+    pub fn detach(mut self) {
+        self.registry = Weak::new();
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let registry = match self.registry.upgrade() {
+            Some(r) => r,
+            None => return, // the client's gone already
+        };
+
+        let lock = registry.lock();
+        if let Ok(mut reg) = lock {
+            reg.listeners.retain(|l| l.id != self.id);
+            if reg.dispatching {
+                reg.removed.push(self.id);
+            }
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct EventDispatcher {
+    registry: Arc<Mutex<ListenerRegistry>>,
+}
+
+impl EventDispatcher {
+    pub fn new() -> EventDispatcher {
+        EventDispatcher::default()
+    }
+
+    pub fn subscribe(
+        &self,
+        filter: Option<ClientEventKind>,
+        callback: ClientEventListener,
+    ) -> Subscription {
+        let mut reg = self.registry.lock().unwrap();
+        let id = reg.next_id;
+        reg.next_id += 1;
+        reg.listeners.push(Listener {
+            id,
+            filter,
+            callback,
+        });
+
+        Subscription {
+            registry: Arc::downgrade(&self.registry),
+            id,
+        }
+    }
+
+    pub fn dispatch(&self, evt: &ClientEvent) {
+        let mut running = {
+            let mut reg = self.registry.lock().unwrap();
+            if reg.listeners.is_empty() {
+                return;
+            }
+
+            reg.dispatching = true;
+            mem::take(&mut reg.listeners)
+        };
+
+        let kind = evt.kind();
+        for listener in running.iter_mut() {
+            if listener.filter.is_none_or(|f| f == kind) {
+                (listener.callback)(evt);
+            }
+        }
+
+        let mut reg = self.registry.lock().unwrap();
+        let removed = mem::take(&mut reg.removed);
+        running.retain(|l| !removed.contains(&l.id));
+
+        // anything subscribed from inside a callback goes after the ones that were already there.
+        running.append(&mut reg.listeners);
+        reg.listeners = running;
+        reg.dispatching = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::PrintLevel;
+
+    // a listener that counts what it gets.
+    fn counter() -> (Arc<Mutex<u32>>, ClientEventListener) {
+        let count = Arc::new(Mutex::new(0));
+        let counted = count.clone();
+        (count, Box::new(move |_| *counted.lock().unwrap() += 1))
+    }
+
+    fn print() -> ClientEvent {
+        ClientEvent::Print(PrintLevel::HIGH, b"hi\n".to_vec())
+    }
+
+    #[test]
+    fn dropping_unsubscribes() {
+        let dispatcher = EventDispatcher::new();
+        let (count, listener) = counter();
+
+        let sub = dispatcher.subscribe(None, listener);
+        dispatcher.dispatch(&print());
+        drop(sub);
+        dispatcher.dispatch(&print());
+
+        assert_eq!(*count.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn detach_keeps_listening() {
+        let dispatcher = EventDispatcher::new();
+        let (count, listener) = counter();
+
+        dispatcher.subscribe(None, listener).detach();
+        dispatcher.dispatch(&print());
+        dispatcher.dispatch(&print());
+
+        assert_eq!(*count.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn filters_by_kind() {
+        let dispatcher = EventDispatcher::new();
+        let (prints, listener) = counter();
+        let _prints = dispatcher.subscribe(Some(ClientEventKind::Print), listener);
+        let (all, listener) = counter();
+        let _all = dispatcher.subscribe(None, listener);
+
+        dispatcher.dispatch(&print());
+        dispatcher.dispatch(&ClientEvent::Disconnect);
+
+        assert_eq!(*prints.lock().unwrap(), 1);
+        assert_eq!(*all.lock().unwrap(), 2);
+    }
+}
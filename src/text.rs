@@ -0,0 +1,127 @@
+// conchars, the console font. the top half is the bottom half again in green/gold, which is
+// how names and server messages get highlighted. below 0x20 it isn't control characters
+// but glyphs: the menu bullets, gold brackets and digits, and the pieces of the
+// "\x1d\x1e\x1e\x1f" separator line.
+const LOW_GLYPHS: [char; 32] = [
+    '•', '•', '•', '•', '•', '•', '•', '•', '•', ' ', '\n', '•', ' ', '>', '•', '•', //
+    '[', ']', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', '•', '─', '─', '─', //
+];
+
+// the arrow at the end of the font.
+const DEL_GLYPH: char = '←';
+
+// anything conchars can't draw.
+const UNKNOWN: u8 = b'?';
+
+fn glyph(b: u8) -> char {
+    match b & 0x7f {
+        low @ 0..=0x1f => LOW_GLYPHS[low as usize],
+        0x7f => DEL_GLYPH,
+        ascii => char::from(ascii),
+    }
+}
+
+fn is_highlighted(b: u8) -> bool {
+    // a highlighted line break is still just a line break.
+    b & 0x80 != 0 && b & 0x7f != b'\n'
+}
+
+// q2 text as plain utf8, highlighting dropped. the gold digits and brackets come out as
+// plain ones and the bullets all look alike, so those don't come back from `encode`.
+pub fn decode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| glyph(*b)).collect()
+}
+
+// same, with the highlighted runs between `open` and `close`. `("**", "**")` makes them
+// bold on discord, `("\x1b[32m", "\x1b[0m")` green in a terminal.
+pub fn decode_marked(bytes: &[u8], open: &str, close: &str) -> String {
+    let mut text = String::with_capacity(bytes.len());
+    let mut highlighted = false;
+
+    for b in bytes {
+        if is_highlighted(*b) != highlighted {
+            highlighted = !highlighted;
+            text.push_str(if highlighted { open } else { close });
+        }
+        text.push(glyph(*b));
+    }
+
+    if highlighted {
+        text.push_str(close);
+    }
+
+    text
+}
+
+// utf8 into something q2 can show. ascii goes as it is, the glyphs `decode` makes go back
+// to conchars, and everything else becomes a '?'.
+pub fn encode(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c {
+            '\n' | ' '..='~' => c as u8,
+            '\t' => b' ',
+            '•' => 0x1c,
+            '─' => 0x1e,
+            DEL_GLYPH => 0x7f,
+            _ => UNKNOWN,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"hello", "hello"),
+            // highlighted is the same letters.
+            (&[0xe8, 0xe9], "hi"),
+            (&[0x8a], "\n"),
+            (&[0x10, 0x12, 0x13, 0x1b, 0x11], "[019]"),
+            (&[0x90, 0x92, 0x91], "[0]"),
+            (&[0x1d, 0x1e, 0x1f], "───"),
+            (&[0x00, 0x1c, 0x8d], "••>"),
+            (&[0x09, 0x0c], "  "),
+            (&[0x7f, 0xff], "←←"),
+        ];
+
+        for (bytes, text) in cases {
+            assert_eq!(decode(bytes), *text, "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn decodes_marked() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"plain", "plain"),
+            (&[b'a', 0xe2, 0xe3, b'd'], "a[bc]d"),
+            // still open at the end.
+            (&[b'a', 0xe2], "a[b]"),
+            // a highlighted line break doesn't split the run up.
+            (&[0xe1, 0x8a, 0xe2], "[a]\n[b]"),
+            (&[0x92, 0x93], "[01]"),
+        ];
+
+        for (bytes, text) in cases {
+            assert_eq!(decode_marked(bytes, "[", "]"), *text, "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        for text in ["hello, world!", "line\nbreak", "• ─ ←", "~{}|"] {
+            assert_eq!(decode(&encode(text)), text);
+        }
+
+        assert_eq!(encode("a\tb"), b"a b");
+        assert_eq!(encode("héllo"), b"h?llo");
+    }
+
+    #[test]
+    fn gold_digits_come_back_plain() {
+        let gold = [0x10, 0x12, 0x1b, 0x11];
+        assert_eq!(encode(&decode(&gold)), b"[09]");
+    }
+}
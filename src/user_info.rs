@@ -35,8 +35,7 @@ impl UserInfo {
     }
 
     pub fn as_string(&self) -> String {
-        let mut kvs: Vec<String> = vec![];
-        kvs.reserve(self.keys.len());
+        let mut kvs: Vec<String> = Vec::with_capacity(self.keys.len());
         for (key, val) in &self.keys {
             kvs.push(format!("\\{key}\\{val}"))
         }